@@ -34,7 +34,10 @@ use glutin_winit::{DisplayBuilder, GlWindow};
 use piet::kurbo::{Affine, BezPath, Point, Rect};
 use piet::RenderContext as _;
 
-use piet_hardware::gpu_types::{AreaCapture, BufferPush, SubtextureWrite, TextureWrite};
+use piet_hardware::gpu_types::{
+    AreaCapture, BufferPush, SubtextureWrite, TextureRead, TextureWrite,
+};
+use piet_hardware::{BlendMode, Capabilities};
 
 use raw_window_handle::HasRawWindowHandle;
 
@@ -44,6 +47,7 @@ use winit::event_loop::EventLoop;
 use winit::window::WindowBuilder;
 
 use std::cell::Cell;
+use std::collections::HashSet;
 use std::ffi::CString;
 use std::fmt;
 use std::mem;
@@ -327,9 +331,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     // Perform drawing.
                     draw(&mut render_context).unwrap();
+                    drop(render_context);
 
                     // Swap buffers.
                     surface.swap_buffers(context).unwrap();
+
+                    // The frame has been submitted, so its resources can be reused.
+                    renderer.gpu_flushed(&(), &());
                 }
 
                 // Schedule the next frame.
@@ -384,8 +392,18 @@ struct GlContext {
     // Uniform locations.
     u_transform: gl::types::GLint,
     viewport_size: gl::types::GLint,
+    y_scale: gl::types::GLint,
     tex: gl::types::GLint,
     mask: gl::types::GLint,
+
+    /// Textures that currently have mipmaps.
+    mipmapped: HashSet<gl::types::GLuint>,
+
+    /// The framebuffer used to draw into textures, or zero if it hasn't been created yet.
+    framebuffer: gl::types::GLuint,
+
+    /// The texture that we are currently drawing into, if any.
+    render_target: Option<gl::types::GLuint>,
 }
 
 #[derive(Clone)]
@@ -517,6 +535,11 @@ impl GlContext {
             gl::GetUniformLocation(program, name.as_ptr())
         };
 
+        let y_scale = unsafe {
+            let name = CString::new("yScale").unwrap();
+            gl::GetUniformLocation(program, name.as_ptr())
+        };
+
         let tex = unsafe {
             let name = CString::new("tex").unwrap();
             gl::GetUniformLocation(program, name.as_ptr())
@@ -534,8 +557,12 @@ impl GlContext {
             render_program: program,
             u_transform,
             viewport_size,
+            y_scale,
             tex,
             mask,
+            mipmapped: HashSet::new(),
+            framebuffer: 0,
+            render_target: None,
         }
     }
 
@@ -649,7 +676,7 @@ impl piet_hardware::GpuContext for GlContext {
                     gl::TexParameterfv(
                         gl::TEXTURE_2D,
                         gl::TEXTURE_BORDER_COLOR,
-                        [r as f32, g as f32, b as f32, a as f32].as_ptr(),
+                        [(r * a) as f32, (g * a) as f32, (b * a) as f32, a as f32].as_ptr(),
                     );
 
                     (gl::CLAMP_TO_EDGE, gl::CLAMP_TO_EDGE)
@@ -665,6 +692,16 @@ impl piet_hardware::GpuContext for GlContext {
         }
     }
 
+    fn delete_texture(&mut self, texture: Self::Texture) {
+        self.assert_context();
+        self.mipmapped.remove(&texture);
+
+        unsafe {
+            gl::DeleteTextures(1, &texture);
+            gl_error();
+        }
+    }
+
     fn write_texture(
         &mut self,
         TextureWrite {
@@ -675,16 +712,18 @@ impl piet_hardware::GpuContext for GlContext {
             format,
             data,
         }: TextureWrite<'_, Self>,
-    ) {
+    ) -> Result<(), Self::Error> {
         self.assert_context();
+        self.mipmapped.remove(texture);
 
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, *texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
 
             let (internal_format, format, ty) = match format {
                 piet::ImageFormat::RgbaSeparate => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
                 piet::ImageFormat::RgbaPremul => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
-                _ => panic!("unsupported image format"),
+                format => return Err(GlError(format!("unsupported image format {:?}", format))),
             };
 
             let (width, height) = size;
@@ -703,6 +742,7 @@ impl piet_hardware::GpuContext for GlContext {
                 ty,
                 data_ptr,
             );
+            gl_result()
         }
     }
 
@@ -717,15 +757,32 @@ impl piet_hardware::GpuContext for GlContext {
             format,
             data,
         }: SubtextureWrite<'_, Self>,
-    ) {
+    ) -> Result<(), Self::Error> {
         self.assert_context();
+        let mipmapped = self.mipmapped.remove(texture);
 
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, *texture);
 
+            // The old mipmaps are out of date, so stop sampling them.
+            if mipmapped {
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
+
+                let mut min_mode = 0;
+                gl::GetTexParameteriv(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, &mut min_mode);
+                if min_mode == gl::LINEAR_MIPMAP_LINEAR as gl::types::GLint {
+                    gl::TexParameteri(
+                        gl::TEXTURE_2D,
+                        gl::TEXTURE_MIN_FILTER,
+                        gl::LINEAR as gl::types::GLint,
+                    );
+                }
+            }
+
             let (format, ty) = match format {
                 piet::ImageFormat::RgbaSeparate => (gl::RGBA, gl::UNSIGNED_BYTE),
-                _ => panic!("unsupported image format"),
+                piet::ImageFormat::RgbaPremul => (gl::RGBA, gl::UNSIGNED_BYTE),
+                format => return Err(GlError(format!("unsupported image format {:?}", format))),
             };
 
             let (width, height) = size;
@@ -742,6 +799,7 @@ impl piet_hardware::GpuContext for GlContext {
                 ty,
                 data.as_ptr() as *const _,
             );
+            gl_result()
         }
     }
 
@@ -750,7 +808,7 @@ impl piet_hardware::GpuContext for GlContext {
         _device: &(),
         texture: &Self::Texture,
         interpolation: piet::InterpolationMode,
-    ) {
+    ) -> Result<(), Self::Error> {
         self.assert_context();
 
         let mode = match interpolation {
@@ -758,6 +816,14 @@ impl piet_hardware::GpuContext for GlContext {
             piet::InterpolationMode::NearestNeighbor => gl::NEAREST,
         };
 
+        // Sample between mipmap levels if we have them.
+        let min_mode = match interpolation {
+            piet::InterpolationMode::Bilinear if self.mipmapped.contains(texture) => {
+                gl::LINEAR_MIPMAP_LINEAR
+            }
+            _ => mode,
+        };
+
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, *texture);
             gl::TexParameteri(
@@ -768,10 +834,52 @@ impl piet_hardware::GpuContext for GlContext {
             gl::TexParameteri(
                 gl::TEXTURE_2D,
                 gl::TEXTURE_MIN_FILTER,
-                mode as gl::types::GLint,
+                min_mode as gl::types::GLint,
             );
             //gl::BindTexture(gl::TEXTURE_2D, 0);
+            gl_result()
+        }
+    }
+
+    fn bind_render_target(
+        &mut self,
+        _device: &(),
+        _queue: &(),
+        target: Option<&Self::Texture>,
+    ) -> Result<bool, Self::Error> {
+        self.assert_context();
+
+        unsafe {
+            match target {
+                Some(texture) => {
+                    if self.framebuffer == 0 {
+                        gl::GenFramebuffers(1, &mut self.framebuffer);
+                    }
+
+                    gl::BindFramebuffer(gl::FRAMEBUFFER, self.framebuffer);
+                    gl::FramebufferTexture2D(
+                        gl::FRAMEBUFFER,
+                        gl::COLOR_ATTACHMENT0,
+                        gl::TEXTURE_2D,
+                        *texture,
+                        0,
+                    );
+
+                    let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+                    if status != gl::FRAMEBUFFER_COMPLETE {
+                        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                        self.render_target = None;
+                        return Err(GlError(format!("incomplete framebuffer: {:#x}", status)));
+                    }
+                }
+                None => gl::BindFramebuffer(gl::FRAMEBUFFER, 0),
+            }
+
+            self.render_target = target.copied();
+            gl_result()?;
         }
+
+        Ok(true)
     }
 
     fn capture_area(
@@ -782,23 +890,25 @@ impl piet_hardware::GpuContext for GlContext {
             texture,
             offset,
             size,
-            bitmap_scale: scale,
+            bitmap_scale: _,
         }: AreaCapture<'_, Self>,
     ) -> Result<(), Self::Error> {
         // Use glReadPixels to read into the texture.
         self.assert_context();
 
         unsafe {
-            let (x, y) = offset;
-            let (width, height) = size;
-            let (x, y, width, height) = (
-                (x as f64 * scale) as i32,
-                (y as f64 * scale) as i32,
-                (width as f64 * scale) as i32,
-                (height as f64 * scale) as i32,
-            );
+            // OpenGL's origin is in the bottom left, so flip the Y coordinate. Textures that we
+            // draw into are already stored top to bottom.
+            let mut viewport = [0; 4];
+            gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
+
+            let flip = self.render_target.is_none();
+            let (x, y) = (offset.0 as i32, offset.1 as i32);
+            let (width, height) = (size.0 as i32, size.1 as i32);
+            let y = if flip { viewport[3] - y - height } else { y };
             let mut buffer = vec![0u8; (width * height * 4) as usize];
 
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
             gl::ReadPixels(
                 x as _,
                 y as _,
@@ -811,33 +921,118 @@ impl piet_hardware::GpuContext for GlContext {
             gl_error();
 
             // Flip the image.
-            let stride = width as usize * 4;
-            let mut row = vec![0u8; stride];
-            for i in 0..(height / 2) {
-                let top = i as usize;
-                let bottom = (height - i - 1) as usize;
-
-                let top_start = top * stride;
-                let bottom_start = bottom * stride;
-
-                row.copy_from_slice(&buffer[top_start..(top_start + stride)]);
-                buffer.copy_within(bottom_start..(bottom_start + stride), top_start);
-                buffer[bottom_start..(bottom_start + stride)].copy_from_slice(&row);
+            if flip {
+                let stride = width as usize * 4;
+                let mut row = vec![0u8; stride];
+                for i in 0..(height / 2) {
+                    let top = i as usize;
+                    let bottom = (height - i - 1) as usize;
+
+                    let top_start = top * stride;
+                    let bottom_start = bottom * stride;
+
+                    row.copy_from_slice(&buffer[top_start..(top_start + stride)]);
+                    buffer.copy_within(bottom_start..(bottom_start + stride), top_start);
+                    buffer[bottom_start..(bottom_start + stride)].copy_from_slice(&row);
+                }
             }
 
             // Write the image to the texture.
-            self.write_subtexture(SubtextureWrite {
+            self.write_texture(TextureWrite {
                 device: &(),
                 queue: &(),
                 texture,
-                offset,
                 size,
-                format: piet::ImageFormat::RgbaSeparate,
-                data: &buffer,
-            });
+                format: piet::ImageFormat::RgbaPremul,
+                data: Some(&buffer),
+            })
         }
+    }
 
-        Ok(())
+    fn generate_mipmaps(
+        &mut self,
+        _device: &(),
+        _queue: &(),
+        texture: &Self::Texture,
+    ) -> Result<bool, Self::Error> {
+        self.assert_context();
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, *texture);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 1000);
+            gl::GenerateMipmap(gl::TEXTURE_2D);
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_MIN_FILTER,
+                gl::LINEAR_MIPMAP_LINEAR as gl::types::GLint,
+            );
+            gl_error();
+        }
+
+        self.mipmapped.insert(*texture);
+        Ok(true)
+    }
+
+    fn read_texture(
+        &mut self,
+        TextureRead {
+            device: (),
+            queue: (),
+            texture,
+            offset,
+            size,
+            data,
+        }: TextureRead<'_, Self>,
+    ) -> Result<bool, Self::Error> {
+        self.assert_context();
+
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, *texture);
+
+            // Read the entire texture, then copy out the requested area.
+            let (mut width, mut height) = (0, 0);
+            gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_WIDTH, &mut width);
+            gl::GetTexLevelParameteriv(gl::TEXTURE_2D, 0, gl::TEXTURE_HEIGHT, &mut height);
+
+            let mut buffer = vec![0u8; width as usize * height as usize * 4];
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            gl::GetTexImage(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA,
+                gl::UNSIGNED_BYTE,
+                buffer.as_mut_ptr() as *mut _,
+            );
+            gl_error();
+
+            let stride = width as usize * 4;
+            let row_len = size.0 as usize * 4;
+            for (row, out) in data.chunks_exact_mut(row_len).enumerate() {
+                let start = (offset.1 as usize + row) * stride + offset.0 as usize * 4;
+                out.copy_from_slice(&buffer[start..start + row_len]);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn capabilities(&mut self, _device: &()) -> Capabilities {
+        self.assert_context();
+
+        let mut capabilities = Capabilities::default();
+        capabilities.formats = vec![
+            piet::ImageFormat::RgbaPremul,
+            piet::ImageFormat::RgbaSeparate,
+        ];
+
+        unsafe {
+            let mut samples = 0;
+            gl::GetIntegerv(gl::SAMPLES, &mut samples);
+            capabilities.msaa_samples = samples.max(1) as u32;
+            gl_error();
+        }
+
+        capabilities
     }
 
     fn max_texture_size(&mut self, _device: &()) -> (u32, u32) {
@@ -850,6 +1045,16 @@ impl piet_hardware::GpuContext for GlContext {
         }
     }
 
+    fn delete_vertex_buffer(&mut self, buffer: Self::VertexBuffer) {
+        self.assert_context();
+
+        unsafe {
+            gl::DeleteVertexArrays(1, &buffer.vao);
+            gl::DeleteBuffers(2, [buffer.vbo, buffer.ebo].as_ptr());
+            gl_error();
+        }
+    }
+
     fn create_vertex_buffer(&mut self, _device: &()) -> Result<Self::VertexBuffer, Self::Error> {
         self.assert_context();
 
@@ -887,9 +1092,10 @@ impl piet_hardware::GpuContext for GlContext {
             let atex_name = CString::new("aTexCoord").unwrap();
             let atex_coord = gl::GetAttribLocation(self.render_program, atex_name.as_ptr() as _);
             gl::EnableVertexAttribArray(atex_coord as _);
+            // Read `uv` and `q` together.
             gl::VertexAttribPointer(
                 atex_coord as _,
-                2,
+                3,
                 gl::FLOAT,
                 gl::FALSE,
                 stride,
@@ -928,7 +1134,7 @@ impl piet_hardware::GpuContext for GlContext {
         buffer: &Self::VertexBuffer,
         vertices: &[piet_hardware::Vertex],
         indices: &[u32],
-    ) {
+    ) -> Result<(), Self::Error> {
         self.assert_context();
 
         unsafe {
@@ -946,8 +1152,8 @@ impl piet_hardware::GpuContext for GlContext {
                 indices.as_ptr() as *const _,
                 gl::DYNAMIC_DRAW,
             );
-            gl_error();
             buffer.num_indices.set(indices.len() as _);
+            gl_result()
         }
     }
 
@@ -962,8 +1168,15 @@ impl piet_hardware::GpuContext for GlContext {
             transform,
             viewport_size,
             clip,
+            blend_mode,
+            material,
         }: BufferPush<'_, Self>,
     ) -> Result<(), Self::Error> {
+        // This example doesn't register any materials.
+        if let Some(material) = material {
+            return Err(GlError(format!("unknown material {}", material.id())));
+        }
+
         unsafe {
             // Use our program.
             gl::UseProgram(self.render_program);
@@ -973,6 +1186,14 @@ impl piet_hardware::GpuContext for GlContext {
             gl::Viewport(0, 0, width as i32, height as i32);
             gl::Uniform2f(self.viewport_size, width as f32, height as f32);
 
+            // Textures store their first row at the top, so draw into them upside down.
+            let y_scale = if self.render_target.is_some() {
+                -1.0
+            } else {
+                1.0
+            };
+            gl::Uniform1f(self.y_scale, y_scale);
+
             // Set the scissor rect.
             let (sx, sy, s_width, s_height) = match clip {
                 Some(Rect { x0, y0, x1, y1 }) => (x0, y0, x1 - x0, y1 - y0),
@@ -998,9 +1219,35 @@ impl piet_hardware::GpuContext for GlContext {
             gl::BindTexture(gl::TEXTURE_2D, *mask_texture);
             gl::Uniform1i(self.mask, 0);
 
-            // Set the blend mode.
+            // Set the blend mode. Separable blend modes that we can't approximate with
+            // fixed-function blending fall back to source-over.
+            let (equation, src, dst) = match blend_mode {
+                BlendMode::Clear => (gl::FUNC_ADD, gl::ZERO, gl::ZERO),
+                BlendMode::Source => (gl::FUNC_ADD, gl::ONE, gl::ZERO),
+                BlendMode::Destination => (gl::FUNC_ADD, gl::ZERO, gl::ONE),
+                BlendMode::DestinationOver => (gl::FUNC_ADD, gl::ONE_MINUS_DST_ALPHA, gl::ONE),
+                BlendMode::SourceIn => (gl::FUNC_ADD, gl::DST_ALPHA, gl::ZERO),
+                BlendMode::DestinationIn => (gl::FUNC_ADD, gl::ZERO, gl::SRC_ALPHA),
+                BlendMode::SourceOut => (gl::FUNC_ADD, gl::ONE_MINUS_DST_ALPHA, gl::ZERO),
+                BlendMode::DestinationOut => (gl::FUNC_ADD, gl::ZERO, gl::ONE_MINUS_SRC_ALPHA),
+                BlendMode::SourceAtop => (gl::FUNC_ADD, gl::DST_ALPHA, gl::ONE_MINUS_SRC_ALPHA),
+                BlendMode::DestinationAtop => {
+                    (gl::FUNC_ADD, gl::ONE_MINUS_DST_ALPHA, gl::SRC_ALPHA)
+                }
+                BlendMode::Xor => (
+                    gl::FUNC_ADD,
+                    gl::ONE_MINUS_DST_ALPHA,
+                    gl::ONE_MINUS_SRC_ALPHA,
+                ),
+                BlendMode::Plus => (gl::FUNC_ADD, gl::ONE, gl::ONE),
+                BlendMode::Multiply => (gl::FUNC_ADD, gl::DST_COLOR, gl::ONE_MINUS_SRC_ALPHA),
+                BlendMode::Screen => (gl::FUNC_ADD, gl::ONE, gl::ONE_MINUS_SRC_COLOR),
+                BlendMode::Lighten => (gl::MAX, gl::ONE, gl::ONE),
+                _ => (gl::FUNC_ADD, gl::ONE, gl::ONE_MINUS_SRC_ALPHA),
+            };
             gl::Enable(gl::BLEND);
-            gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            gl::BlendEquation(equation);
+            gl::BlendFunc(src, dst);
 
             // Set vertex attributes.
             gl::BindVertexArray(vertex_buffer.vao);
@@ -1030,6 +1277,12 @@ impl piet_hardware::GpuContext for GlContext {
 }
 
 fn gl_error() {
+    if let Err(err) = gl_result() {
+        log::error!("{}", err);
+    }
+}
+
+fn gl_result() -> Result<(), GlError> {
     let err = unsafe { gl::GetError() };
 
     if err != gl::NO_ERROR {
@@ -1045,23 +1298,26 @@ fn gl_error() {
             _ => "Unknown GL error",
         };
 
-        log::error!("GL error: {}", error_str)
+        return Err(GlError(error_str.into()));
     }
+
+    Ok(())
 }
 
 const VERTEX_SHADER: &str = "
 #version 330 core
 
 in vec2 aPos;
-in vec2 aTexCoord;
+in vec3 aTexCoord;
 in vec4 aColor;
 
 out vec4 rgbaColor;
-out vec2 fTexCoord;
+out vec3 fTexCoord;
 out vec2 fMaskCoord;
 
 uniform mat3 transform;
 uniform vec2 viewportSize;
+uniform float yScale;
 
 void main() {
     // Transform the vertex position.
@@ -1071,7 +1327,7 @@ void main() {
     // Transform to screen-space coordinates.
     gl_Position = vec4(
         (2.0 * pos.x / viewportSize.x) - 1.0,
-        1.0 - (2.0 * pos.y / viewportSize.y),
+        (1.0 - (2.0 * pos.y / viewportSize.y)) * yScale,
         0.0,
         1.0
     );
@@ -1082,7 +1338,9 @@ void main() {
         1.0 - (pos.y / viewportSize.y)
     );
 
-    rgbaColor = aColor / 255.0;
+    // Textures use premultiplied alpha, so premultiply the vertex color as well.
+    vec4 color = aColor / 255.0;
+    rgbaColor = vec4(color.rgb * color.a, color.a);
     fTexCoord = aTexCoord;
 }
 ";
@@ -1091,14 +1349,14 @@ const FRAGMENT_SHADER: &str = "
 #version 330 core
 
 in vec4 rgbaColor;
-in vec2 fTexCoord;
+in vec3 fTexCoord;
 in vec2 fMaskCoord;
 
 uniform sampler2D tex;
 uniform sampler2D mask;
 
 void main() {
-    vec4 textureColor = texture2D(tex, fTexCoord);
+    vec4 textureColor = texture2D(tex, fTexCoord.xy / fTexCoord.z);
     vec4 mainColor = rgbaColor * textureColor;
 
     vec4 maskColor = texture2D(mask, fMaskCoord);
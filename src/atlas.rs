@@ -19,7 +19,8 @@
 //! The text atlas, which is used to cache glyphs.
 
 use super::gpu_backend::{GpuContext, RepeatStrategy};
-use super::resources::Texture;
+use super::image::premultiply;
+use super::resources::{DeletionQueue, Texture};
 use super::ResultExt;
 
 use ahash::RandomState;
@@ -77,25 +78,30 @@ impl<C: GpuContext + ?Sized> Atlas<C> {
         context: &mut C,
         device: &C::Device,
         queue: &C::Queue,
+        deletions: &DeletionQueue<C>,
+        repeat: RepeatStrategy,
     ) -> Result<Self, Pierror> {
         let (max_width, max_height) = context.max_texture_size(device);
         let texture = Texture::new(
             context,
             device,
+            deletions,
             InterpolationMode::Bilinear,
-            RepeatStrategy::Color(piet::Color::TRANSPARENT),
+            repeat,
         )
         .piet_err()?;
 
         // Initialize the texture to be transparent.
-        texture.write_texture(
-            context,
-            device,
-            queue,
-            (max_width, max_height),
-            piet::ImageFormat::RgbaPremul,
-            None,
-        );
+        texture
+            .write_texture(
+                context,
+                device,
+                queue,
+                (max_width, max_height),
+                piet::ImageFormat::RgbaPremul,
+                None,
+            )
+            .piet_err()?;
 
         Ok(Atlas {
             texture: Rc::new(texture),
@@ -181,8 +187,13 @@ impl<C: GpuContext + ?Sized> Atlas<C> {
                             .iter_mut()
                             .zip(sw_image.data.chunks(4))
                             .for_each(|(buf, input)| {
-                                let color =
-                                    u32::from_ne_bytes([input[0], input[1], input[2], input[3]]);
+                                let alpha = input[3];
+                                let color = u32::from_ne_bytes([
+                                    premultiply(input[0], alpha),
+                                    premultiply(input[1], alpha),
+                                    premultiply(input[2], alpha),
+                                    alpha,
+                                ]);
                                 *buf = color;
                             });
                     }
@@ -192,7 +203,7 @@ impl<C: GpuContext + ?Sized> Atlas<C> {
                             .iter_mut()
                             .zip(sw_image.data.iter())
                             .for_each(|(buf, input)| {
-                                let color = u32::from_ne_bytes([255, 255, 255, *input]);
+                                let color = u32::from_ne_bytes([*input, *input, *input, *input]);
                                 *buf = color;
                             });
                     }
@@ -213,15 +224,22 @@ impl<C: GpuContext + ?Sized> Atlas<C> {
                     })?;
 
                 // Insert the glyph into the texture.
-                self.texture.write_subtexture(
-                    context,
-                    device,
-                    queue,
-                    (alloc.rectangle.min.x as u32, alloc.rectangle.min.y as u32),
-                    (width, height),
-                    piet::ImageFormat::RgbaPremul,
-                    bytemuck::cast_slice::<_, u8>(&buffer),
-                );
+                let written = self
+                    .texture
+                    .write_subtexture(
+                        context,
+                        device,
+                        queue,
+                        (alloc.rectangle.min.x as u32, alloc.rectangle.min.y as u32),
+                        (width, height),
+                        piet::ImageFormat::RgbaPremul,
+                        bytemuck::cast_slice::<_, u8>(&buffer),
+                    )
+                    .piet_err();
+                if written.is_err() {
+                    self.allocator.deallocate(alloc.id);
+                }
+                written?;
 
                 // Insert the allocation into the map.
                 let alloc = v.insert(Position {
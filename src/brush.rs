@@ -19,22 +19,39 @@
 //! The brush types used by `piet-hardware`.
 
 use super::gpu_backend::{GpuContext, RepeatStrategy, Vertex};
+use super::gradient::{self, GradientOptions};
 use super::image::Image;
-use super::resources::{self, Texture};
+use super::material::Material;
+use super::resources::{self, DeletionQueue, Texture};
 use super::{RenderContext, ResultExt, UV_WHITE};
 
 use piet::kurbo::{Affine, Circle, Point, Rect, Shape};
-use piet::{Error as Pierror, FixedGradient, FixedLinearGradient, FixedRadialGradient};
+use piet::{
+    Error as Pierror, FixedGradient, FixedLinearGradient, FixedRadialGradient, GradientStop,
+};
 
 use std::borrow::Cow;
 
 /// The brush type used by the GPU renderer.
 #[derive(Debug)]
-pub struct Brush<C: GpuContext + ?Sized>(BrushInner<C>);
+pub struct Brush<C: GpuContext + ?Sized> {
+    /// The underlying fill of the brush.
+    inner: BrushInner<C>,
+
+    /// The alpha multiplier applied to everything drawn with this brush.
+    alpha: f64,
+
+    /// The color that everything drawn with this brush is multiplied by.
+    tint: Option<piet::Color>,
+}
 
 impl<C: GpuContext + ?Sized> Clone for Brush<C> {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            inner: self.inner.clone(),
+            alpha: self.alpha,
+            tint: self.tint,
+        }
     }
 }
 
@@ -54,6 +71,9 @@ enum BrushInner<C: GpuContext + ?Sized> {
         /// The fixed gradient to apply.
         gradient: FixedGradient,
     },
+
+    /// A material that the backend shades.
+    Material(Material),
 }
 
 impl<C: GpuContext + ?Sized> piet::IntoBrush<RenderContext<'_, '_, '_, C>> for Brush<C> {
@@ -69,7 +89,68 @@ impl<C: GpuContext + ?Sized> piet::IntoBrush<RenderContext<'_, '_, '_, C>> for B
 impl<C: GpuContext + ?Sized> Brush<C> {
     /// Create a new solid brush.
     pub(crate) fn solid(color: piet::Color) -> Self {
-        Self(BrushInner::Solid(color))
+        Self::from_inner(BrushInner::Solid(color))
+    }
+
+    /// Create a new brush that fills shapes with a material.
+    pub(crate) fn material(material: Material) -> Self {
+        Self::from_inner(BrushInner::Material(material))
+    }
+
+    /// Create a new brush with no alpha or tint modifiers.
+    fn from_inner(inner: BrushInner<C>) -> Self {
+        Self {
+            inner,
+            alpha: 1.0,
+            tint: None,
+        }
+    }
+
+    /// Get a copy of this brush with its opacity multiplied by `alpha`.
+    ///
+    /// `alpha` is clamped to the range `[0.0, 1.0]`. This applies to solid colors as well as
+    /// gradients and images, so it can be used to fade out content without re-creating the
+    /// underlying textures.
+    pub fn with_alpha(mut self, alpha: f64) -> Self {
+        self.alpha = (self.alpha * alpha).clamp(0.0, 1.0);
+        self
+    }
+
+    /// Get a copy of this brush with its colors multiplied by `tint`.
+    ///
+    /// The alpha channel of the tint is multiplied into the brush's opacity as well. Tinting a
+    /// brush that is already tinted combines both tints.
+    pub fn with_tint(mut self, tint: piet::Color) -> Self {
+        self.tint = Some(match self.tint {
+            Some(old) => multiply_colors(old, tint),
+            None => tint,
+        });
+        self
+    }
+
+    /// Get the alpha multiplier of this brush.
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    /// Get the tint color of this brush, if there is one.
+    pub fn tint(&self) -> Option<piet::Color> {
+        self.tint
+    }
+
+    /// Apply the alpha multiplier and the tint to a color.
+    fn modulate(&self, color: piet::Color) -> piet::Color {
+        let color = match self.tint {
+            Some(tint) => multiply_colors(color, tint),
+            None => color,
+        };
+
+        if self.alpha < 1.0 {
+            let (r, g, b, a) = color.as_rgba();
+            piet::Color::rgba(r, g, b, a * self.alpha)
+        } else {
+            color
+        }
     }
 
     /// Create a new brush from a linear gradient.
@@ -77,11 +158,16 @@ impl<C: GpuContext + ?Sized> Brush<C> {
         context: &mut C,
         device: &C::Device,
         queue: &C::Queue,
-        gradient: FixedLinearGradient,
+        deletions: &DeletionQueue<C>,
+        mut gradient: FixedLinearGradient,
+        options: GradientOptions,
     ) -> Result<Self, Pierror> {
+        gradient::interpolate_stops(&mut gradient.stops, options.interpolation);
+
         let texture = Texture::new(
             context,
             device,
+            deletions,
             piet::InterpolationMode::Bilinear,
             RepeatStrategy::Clamp,
         )
@@ -90,7 +176,18 @@ impl<C: GpuContext + ?Sized> Brush<C> {
         let (gradient, transform) = straighten_gradient(gradient);
         let bounds = Rect::from_points(gradient.start, gradient.end);
         let offset = -bounds.origin().to_vec2();
-        texture.write_linear_gradient(context, device, queue, &gradient, bounds.size(), offset)?;
+        let dither = options
+            .dithering
+            .should_dither(&gradient.stops, (gradient.end - gradient.start).hypot());
+        texture.write_linear_gradient(
+            context,
+            device,
+            queue,
+            &gradient,
+            bounds.size(),
+            offset,
+            dither,
+        )?;
         Ok(Self::textured(texture, bounds.size(), transform, gradient))
     }
 
@@ -99,11 +196,16 @@ impl<C: GpuContext + ?Sized> Brush<C> {
         context: &mut C,
         device: &C::Device,
         queue: &C::Queue,
-        gradient: FixedRadialGradient,
+        deletions: &DeletionQueue<C>,
+        mut gradient: FixedRadialGradient,
+        options: GradientOptions,
     ) -> Result<Self, Pierror> {
+        gradient::interpolate_stops(&mut gradient.stops, options.interpolation);
+
         let texture = Texture::new(
             context,
             device,
+            deletions,
             piet::InterpolationMode::Bilinear,
             RepeatStrategy::Clamp,
         )
@@ -113,7 +215,18 @@ impl<C: GpuContext + ?Sized> Brush<C> {
         let offset = -bounds.origin().to_vec2();
         let transform = scale_and_offset(bounds.size(), bounds.origin());
 
-        texture.write_radial_gradient(context, device, queue, &gradient, bounds.size(), offset)?;
+        let dither = options
+            .dithering
+            .should_dither(&gradient.stops, gradient.radius);
+        texture.write_radial_gradient(
+            context,
+            device,
+            queue,
+            &gradient,
+            bounds.size(),
+            offset,
+            dither,
+        )?;
         Ok(Self::textured(texture, bounds.size(), transform, gradient))
     }
 
@@ -125,9 +238,10 @@ impl<C: GpuContext + ?Sized> Brush<C> {
         gradient: impl Into<FixedGradient>,
     ) -> Self {
         // Create a new image.
-        let image = Image::new(texture, size);
+        let pixel_size = ((size.width as u32).max(1), (size.height as u32).max(1));
+        let image = Image::new(texture, size, pixel_size, piet::ImageFormat::RgbaPremul);
 
-        Self(BrushInner::Texture {
+        Self::from_inner(BrushInner::Texture {
             image,
             transform,
             gradient: gradient.into(),
@@ -136,22 +250,33 @@ impl<C: GpuContext + ?Sized> Brush<C> {
 
     /// Get the texture associated with this brush.
     pub(crate) fn texture(&self, _size: (u32, u32)) -> Option<&Image<C>> {
-        match self.0 {
-            BrushInner::Solid(_) => None,
+        match self.inner {
+            BrushInner::Solid(_) | BrushInner::Material(_) => None,
             BrushInner::Texture { ref image, .. } => Some(image),
         }
     }
 
+    /// Get the material associated with this brush.
+    pub(crate) fn as_material(&self) -> Option<&Material> {
+        match self.inner {
+            BrushInner::Material(ref material) => Some(material),
+            _ => None,
+        }
+    }
+
     /// Transform a two-dimensional point into a vertex using this brush.
     pub(crate) fn make_vertex(&self, point: [f32; 2]) -> Vertex {
-        match self.0 {
+        let to_array = |color: piet::Color| {
+            let (r, g, b, a) = color.as_rgba8();
+            [r, g, b, a]
+        };
+
+        match self.inner {
             BrushInner::Solid(color) => Vertex {
                 pos: point,
                 uv: UV_WHITE,
-                color: {
-                    let (r, g, b, a) = color.as_rgba8();
-                    [r, g, b, a]
-                },
+                q: 1.0,
+                color: to_array(self.modulate(color)),
             },
 
             BrushInner::Texture { transform, .. } => {
@@ -159,16 +284,34 @@ impl<C: GpuContext + ?Sized> Brush<C> {
                 Vertex {
                     pos: point,
                     uv: [uv.x as f32, uv.y as f32],
-                    color: [0xFF, 0xFF, 0xFF, 0xFF],
+                    q: 1.0,
+                    color: to_array(self.modulate(piet::Color::WHITE)),
+                }
+            }
+
+            BrushInner::Material(ref material) => {
+                let uv = material.map_point(Point::new(point[0] as f64, point[1] as f64));
+                Vertex {
+                    pos: point,
+                    uv: [uv.x as f32, uv.y as f32],
+                    q: 1.0,
+                    color: to_array(self.modulate(piet::Color::WHITE)),
                 }
             }
         }
     }
 
     pub(crate) fn to_shader(&self) -> Option<tiny_skia::Shader<'static>> {
-        match &self.0 {
+        let convert_stop = |stop: &GradientStop| {
+            resources::convert_to_ts_gradient_stop(&GradientStop {
+                pos: stop.pos,
+                color: self.modulate(stop.color),
+            })
+        };
+
+        match &self.inner {
             BrushInner::Solid(color) => Some(tiny_skia::Shader::SolidColor(
-                resources::convert_to_ts_color(*color),
+                resources::convert_to_ts_color(self.modulate(*color)),
             )),
             BrushInner::Texture {
                 gradient: FixedGradient::Linear(linear),
@@ -176,11 +319,7 @@ impl<C: GpuContext + ?Sized> Brush<C> {
             } => tiny_skia::LinearGradient::new(
                 resources::convert_to_ts_point(linear.start),
                 resources::convert_to_ts_point(linear.end),
-                linear
-                    .stops
-                    .iter()
-                    .map(resources::convert_to_ts_gradient_stop)
-                    .collect(),
+                linear.stops.iter().map(convert_stop).collect(),
                 tiny_skia::SpreadMode::Pad,
                 tiny_skia::Transform::identity(),
             ),
@@ -191,14 +330,14 @@ impl<C: GpuContext + ?Sized> Brush<C> {
                 resources::convert_to_ts_point(radial.center + radial.origin_offset),
                 resources::convert_to_ts_point(radial.center),
                 radial.radius as f32,
-                radial
-                    .stops
-                    .iter()
-                    .map(resources::convert_to_ts_gradient_stop)
-                    .collect(),
+                radial.stops.iter().map(convert_stop).collect(),
                 tiny_skia::SpreadMode::Pad,
                 tiny_skia::Transform::identity(),
             ),
+            // Materials are shaded by the backend, so use their tint instead.
+            BrushInner::Material(_) => Some(tiny_skia::Shader::SolidColor(
+                resources::convert_to_ts_color(self.modulate(piet::Color::WHITE)),
+            )),
         }
     }
 }
@@ -216,6 +355,7 @@ impl<C: GpuContext + ?Sized> Clone for BrushInner<C> {
                 transform: *transform,
                 gradient: gradient.clone(),
             },
+            Self::Material(material) => Self::Material(material.clone()),
         }
     }
 }
@@ -265,6 +405,13 @@ fn straighten_gradient(gradient: FixedLinearGradient) -> (FixedLinearGradient, A
     (new_gradient, transform)
 }
 
+/// Multiply two colors together, channel by channel.
+fn multiply_colors(a: piet::Color, b: piet::Color) -> piet::Color {
+    let (r1, g1, b1, a1) = a.as_rgba();
+    let (r2, g2, b2, a2) = b.as_rgba();
+    piet::Color::rgba(r1 * r2, g1 * g2, b1 * b2, a1 * a2)
+}
+
 fn scale_and_offset(size: kurbo::Size, offset: kurbo::Point) -> Affine {
     Affine::scale_non_uniform(1.0 / size.width, 1.0 / size.height)
         * Affine::translate(-offset.to_vec2())
@@ -0,0 +1,449 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Decoding image files into images, using the `image` crate.
+
+use super::ResultExt;
+
+use image::{DynamicImage, ImageFormat};
+use piet::Error as Pierror;
+
+/// An image decoded into RGBA pixels with separate alpha.
+pub(crate) struct DecodedImage {
+    /// The width of the image.
+    pub(crate) width: usize,
+
+    /// The height of the image.
+    pub(crate) height: usize,
+
+    /// The pixels of the image.
+    pub(crate) data: Vec<u8>,
+}
+
+/// Decode an encoded PNG, JPEG, WebP or GIF image.
+///
+/// The image is rotated and flipped according to its EXIF orientation, if it has one. Only
+/// the first frame of an animated image is decoded.
+pub(crate) fn decode_image(data: &[u8]) -> Result<DecodedImage, Pierror> {
+    let format = image::guess_format(data).piet_err()?;
+    if !matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP | ImageFormat::Gif
+    ) {
+        return Err(Pierror::NotSupported);
+    }
+
+    let image = image::load_from_memory_with_format(data, format).piet_err()?;
+    let image = match find_exif(data, format).and_then(exif_orientation) {
+        Some(orientation) => apply_orientation(image, orientation),
+        None => image,
+    };
+
+    let image = image.into_rgba8();
+    Ok(DecodedImage {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        data: image.into_raw(),
+    })
+}
+
+/// Rotate and flip an image according to its EXIF orientation.
+fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Find the EXIF data embedded in an image file.
+///
+/// The returned data starts with the TIFF header.
+fn find_exif(data: &[u8], format: ImageFormat) -> Option<&[u8]> {
+    match format {
+        ImageFormat::Jpeg => {
+            // Walk the markers until we find the APP1 segment with the EXIF data.
+            let mut pos = 2;
+            while pos + 4 <= data.len() && data[pos] == 0xFF {
+                let marker = data[pos + 1];
+                let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+                let segment = data.get(pos + 4..pos + 2 + len)?;
+
+                if marker == 0xE1 && segment.starts_with(b"Exif\0\0") {
+                    return Some(&segment[6..]);
+                }
+
+                // Stop at the start of the image data.
+                if marker == 0xDA {
+                    break;
+                }
+                pos += 2 + len;
+            }
+
+            None
+        }
+
+        ImageFormat::Png => {
+            // Chunks are a length, a type, the data and a CRC.
+            let mut pos = 8;
+            while pos + 8 <= data.len() {
+                let len = u32::from_be_bytes(data[pos..pos + 4].try_into().ok()?) as usize;
+                let kind = &data[pos + 4..pos + 8];
+                let chunk = data.get(pos + 8..(pos + 8).checked_add(len)?)?;
+
+                match kind {
+                    b"eXIf" => return Some(chunk),
+                    b"IDAT" | b"IEND" => break,
+                    _ => pos += 12 + len,
+                }
+            }
+
+            None
+        }
+
+        ImageFormat::WebP => {
+            // RIFF chunks are a type, a length and the data, padded to an even length.
+            let mut pos = 12;
+            while pos + 8 <= data.len() {
+                let kind = &data[pos..pos + 4];
+                let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into().ok()?) as usize;
+                let chunk = data.get(pos + 8..(pos + 8).checked_add(len)?)?;
+
+                if kind == b"EXIF" {
+                    // Some encoders include the JPEG-style header.
+                    return Some(chunk.strip_prefix(b"Exif\0\0").unwrap_or(chunk));
+                }
+                pos += 8 + len + (len & 1);
+            }
+
+            None
+        }
+
+        _ => None,
+    }
+}
+
+/// Read the orientation tag from EXIF data.
+fn exif_orientation(exif: &[u8]) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x0112;
+
+    let little_endian = match exif.get(..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let read_u16 = |pos: usize| {
+        let bytes = [*exif.get(pos)?, *exif.get(pos + 1)?];
+        Some(if little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    };
+    let read_u32 = |pos: usize| {
+        let bytes = exif.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    };
+
+    // Look through the entries of the first image file directory.
+    let ifd = read_u32(4)? as usize;
+    let count = read_u16(ifd)? as usize;
+    (0..count).find_map(|i| {
+        let entry = ifd + 2 + i * 12;
+        if read_u16(entry)? == ORIENTATION_TAG {
+            read_u16(entry + 8)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{Rgba, RgbaImage};
+
+    /// The tag of the orientation entry.
+    const ORIENTATION: u16 = 0x0112;
+
+    /// Map a displayed pixel to a stored one, given the largest stored coordinates.
+    type SourcePixel = fn(u32, u32, u32, u32) -> (u32, u32);
+
+    /// Build TIFF data with a single IFD that holds an orientation entry.
+    fn tiff(orientation: u16, little_endian: bool) -> Vec<u8> {
+        let u16_bytes = |v: u16| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+        let u32_bytes = |v: u32| {
+            if little_endian {
+                v.to_le_bytes()
+            } else {
+                v.to_be_bytes()
+            }
+        };
+
+        let mut data = Vec::new();
+        data.extend_from_slice(if little_endian { b"II*\0" } else { b"MM\0*" });
+        data.extend_from_slice(&u32_bytes(8));
+
+        // Two entries, with the orientation second.
+        data.extend_from_slice(&u16_bytes(2));
+        for (tag, value) in [(0x0100, 16), (ORIENTATION, orientation)] {
+            data.extend_from_slice(&u16_bytes(tag));
+            data.extend_from_slice(&u16_bytes(3));
+            data.extend_from_slice(&u32_bytes(1));
+            data.extend_from_slice(&u16_bytes(value));
+            data.extend_from_slice(&[0, 0]);
+        }
+        data.extend_from_slice(&u32_bytes(0));
+        data
+    }
+
+    /// Build a JPEG file out of marker segments.
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        for (marker, segment) in segments {
+            data.extend_from_slice(&[0xFF, *marker]);
+            data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(segment);
+        }
+        data.extend_from_slice(&[0xFF, 0xD9]);
+        data
+    }
+
+    /// Build a PNG file out of chunks.
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, chunk) in chunks {
+            data.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+            data.extend_from_slice(*kind);
+            data.extend_from_slice(chunk);
+            data.extend_from_slice(&[0; 4]);
+        }
+        data
+    }
+
+    /// Build a WebP file out of RIFF chunks.
+    fn webp(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut body = b"WEBP".to_vec();
+        for (kind, chunk) in chunks {
+            body.extend_from_slice(*kind);
+            body.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+            body.extend_from_slice(chunk);
+            if chunk.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+
+        let mut data = b"RIFF".to_vec();
+        data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        data.extend_from_slice(&body);
+        data
+    }
+
+    /// Prefix EXIF data with the header used in JPEG files.
+    fn with_header(exif: &[u8]) -> Vec<u8> {
+        [&b"Exif\0\0"[..], exif].concat()
+    }
+
+    /// Read the orientation out of a whole file.
+    fn orientation(data: &[u8], format: ImageFormat) -> Option<u16> {
+        find_exif(data, format).and_then(exif_orientation)
+    }
+
+    #[test]
+    fn tiff_byte_orders() {
+        for little_endian in [true, false] {
+            for value in 1..=8 {
+                assert_eq!(exif_orientation(&tiff(value, little_endian)), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn tiff_malformed() {
+        let exif = tiff(6, true);
+
+        // Every truncation either still finds the entry or gives up.
+        for len in 0..exif.len() {
+            let result = exif_orientation(&exif[..len]);
+            assert!(result.is_none() || result == Some(6), "{len}: {result:?}");
+        }
+        assert_eq!(exif_orientation(&exif[..20]), None);
+
+        // Bad magic.
+        assert_eq!(exif_orientation(b"IM*\0\x08\0\0\0"), None);
+
+        // IFD offsets and entry counts that point past the end.
+        assert_eq!(exif_orientation(b"II*\0\xFF\xFF\xFF\xFF"), None);
+        assert_eq!(exif_orientation(b"II*\0\x08\0\0\0\xFF\xFF"), None);
+        assert_eq!(exif_orientation(b"MM\0*\0\0\0\x08\0\0"), None);
+    }
+
+    #[test]
+    fn jpeg_exif() {
+        for little_endian in [true, false] {
+            let exif = with_header(&tiff(3, little_endian));
+            let data = jpeg(&[(0xE0, b"JFIF\0"), (0xE1, &exif), (0xDA, &[])]);
+            assert_eq!(orientation(&data, ImageFormat::Jpeg), Some(3));
+        }
+
+        // APP1 segments that hold something else, and empty segments, are skipped.
+        let exif = with_header(&tiff(8, true));
+        let data = jpeg(&[(0xE1, b"http://ns.adobe.com/"), (0xE2, &[]), (0xE1, &exif)]);
+        assert_eq!(orientation(&data, ImageFormat::Jpeg), Some(8));
+
+        // EXIF data after the start of the image data is ignored.
+        let data = jpeg(&[(0xDA, &[]), (0xE1, &exif)]);
+        assert_eq!(orientation(&data, ImageFormat::Jpeg), None);
+    }
+
+    #[test]
+    fn jpeg_malformed() {
+        let exif = with_header(&tiff(5, false));
+        let data = jpeg(&[(0xE1, &exif)]);
+        for len in 0..data.len() {
+            let result = orientation(&data[..len], ImageFormat::Jpeg);
+            assert!(result.is_none() || result == Some(5), "{len}: {result:?}");
+        }
+
+        // Segment lengths that are too short to include themselves.
+        for len in [0u8, 1] {
+            let data = [0xFF, 0xD8, 0xFF, 0xE0, 0, len, 0xFF, 0xD9];
+            assert_eq!(orientation(&data, ImageFormat::Jpeg), None);
+        }
+
+        // A segment that runs past the end of the file.
+        let data = [0xFF, 0xD8, 0xFF, 0xE1, 0xFF, 0xFF, b'E', b'x'];
+        assert_eq!(orientation(&data, ImageFormat::Jpeg), None);
+    }
+
+    #[test]
+    fn png_exif() {
+        for little_endian in [true, false] {
+            let exif = tiff(7, little_endian);
+            let data = png(&[(b"IHDR", &[0; 13]), (b"tEXt", &[]), (b"eXIf", &exif)]);
+            assert_eq!(orientation(&data, ImageFormat::Png), Some(7));
+        }
+
+        // EXIF data after the image data is ignored.
+        let data = png(&[(b"IDAT", &[]), (b"eXIf", &tiff(7, true))]);
+        assert_eq!(orientation(&data, ImageFormat::Png), None);
+    }
+
+    #[test]
+    fn png_malformed() {
+        let data = png(&[(b"IHDR", &[0; 13]), (b"eXIf", &tiff(2, true))]);
+        for len in 0..data.len() {
+            let result = orientation(&data[..len], ImageFormat::Png);
+            assert!(result.is_none() || result == Some(2), "{len}: {result:?}");
+        }
+
+        // A chunk length that overflows.
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        data.extend_from_slice(b"eXIf");
+        assert_eq!(orientation(&data, ImageFormat::Png), None);
+
+        // An empty EXIF chunk.
+        let data = png(&[(b"eXIf", &[])]);
+        assert_eq!(orientation(&data, ImageFormat::Png), None);
+    }
+
+    #[test]
+    fn webp_exif() {
+        for little_endian in [true, false] {
+            let exif = tiff(4, little_endian);
+            let data = webp(&[(b"VP8X", &[0; 10]), (b"ICCP", &[1]), (b"EXIF", &exif)]);
+            assert_eq!(orientation(&data, ImageFormat::WebP), Some(4));
+
+            // Some encoders include the JPEG-style header.
+            let data = webp(&[(b"EXIF", &with_header(&exif))]);
+            assert_eq!(orientation(&data, ImageFormat::WebP), Some(4));
+        }
+    }
+
+    #[test]
+    fn webp_malformed() {
+        let data = webp(&[(b"VP8X", &[0; 10]), (b"EXIF", &tiff(6, false))]);
+        for len in 0..data.len() {
+            let result = orientation(&data[..len], ImageFormat::WebP);
+            assert!(result.is_none() || result == Some(6), "{len}: {result:?}");
+        }
+
+        // Empty chunks.
+        let data = webp(&[(b"ICCP", &[]), (b"EXIF", &[])]);
+        assert_eq!(orientation(&data, ImageFormat::WebP), None);
+
+        // A chunk length that overflows.
+        let mut data = b"RIFF\0\0\0\0WEBPEXIF".to_vec();
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(orientation(&data, ImageFormat::WebP), None);
+    }
+
+    #[test]
+    fn orientations() {
+        // A 3x2 image whose pixels hold their own coordinates.
+        let (width, height) = (3, 2);
+        let stored = RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 0, 255]));
+
+        // Where each displayed pixel comes from in the stored image, according to the EXIF
+        // specification.
+        let (w, h) = (width - 1, height - 1);
+        let expected: [SourcePixel; 8] = [
+            |x, y, _, _| (x, y),
+            |x, y, w, _| (w - x, y),
+            |x, y, w, h| (w - x, h - y),
+            |x, y, _, h| (x, h - y),
+            |x, y, _, _| (y, x),
+            |x, y, _, h| (y, h - x),
+            |x, y, w, h| (w - y, h - x),
+            |x, y, w, _| (w - y, x),
+        ];
+
+        for (orientation, source) in (1..=8).zip(expected) {
+            let image = apply_orientation(DynamicImage::ImageRgba8(stored.clone()), orientation)
+                .into_rgba8();
+            let (display_width, display_height) = if orientation >= 5 {
+                (height, width)
+            } else {
+                (width, height)
+            };
+            assert_eq!(image.dimensions(), (display_width, display_height));
+
+            for (x, y, pixel) in image.enumerate_pixels() {
+                let (sx, sy) = source(x, y, w, h);
+                assert_eq!(pixel, stored.get_pixel(sx, sy), "{orientation}: ({x}, {y})");
+            }
+        }
+    }
+}
@@ -18,12 +18,23 @@
 
 //! Defines the GPU backend for piet-hardware.
 
+use super::material::Material;
+
 use piet::kurbo::{Affine, Rect};
 use piet::InterpolationMode;
 
 use std::error::Error;
 
 /// The backend for the GPU renderer.
+///
+/// ## Premultiplied alpha
+///
+/// Every texture that the renderer writes contains RGBA data with premultiplied alpha, so
+/// that bilinear filtering does not bleed the color of transparent pixels into their
+/// neighbors. Vertex colors, on the other hand, have separate alpha. The backend is expected
+/// to premultiply the vertex color, multiply it by the texture and mask samples, and then
+/// blend the result onto the target according to the [`BlendMode`] in [`BufferPush`]. The
+/// default [`BlendMode::SourceOver`] uses the `ONE, ONE_MINUS_SRC_ALPHA` blend function.
 pub trait GpuContext {
     /// A "device" that can be used to render.
     ///
@@ -68,11 +79,24 @@ pub trait GpuContext {
         repeat: RepeatStrategy,
     ) -> Result<Self::Texture, Self::Error>;
 
+    /// Delete a texture.
+    ///
+    /// This is called from [`Source::gpu_flushed`] once the renderer no longer needs the texture,
+    /// so the commands that used it have finished. By default, the texture is just dropped.
+    ///
+    /// [`Source::gpu_flushed`]: crate::Source::gpu_flushed
+    fn delete_texture(&mut self, texture: Self::Texture) {
+        drop(texture);
+    }
+
     /// Write an image to a texture.
-    fn write_texture(&mut self, texture_write: TextureWrite<'_, Self>);
+    fn write_texture(&mut self, texture_write: TextureWrite<'_, Self>) -> Result<(), Self::Error>;
 
     /// Write a sub-image to a texture.
-    fn write_subtexture(&mut self, subtexture_write: SubtextureWrite<'_, Self>);
+    fn write_subtexture(
+        &mut self,
+        subtexture_write: SubtextureWrite<'_, Self>,
+    ) -> Result<(), Self::Error>;
 
     /// Set the interpolation mode for a texture.
     fn set_texture_interpolation(
@@ -80,21 +104,79 @@ pub trait GpuContext {
         device: &Self::Device,
         texture: &Self::Texture,
         interpolation: InterpolationMode,
-    );
+    ) -> Result<(), Self::Error>;
+
+    /// Generate mipmaps for a texture from the image currently written into it.
+    ///
+    /// Once a texture has mipmaps, setting its interpolation mode to
+    /// [`InterpolationMode::Bilinear`] should sample it trilinearly, by blending between the two
+    /// closest mipmap levels. [`InterpolationMode::NearestNeighbor`] should ignore the mipmaps.
+    /// Writing a new image into the texture discards its mipmaps.
+    ///
+    /// Returns `Ok(false)` if the backend does not support mipmapping, which is the default.
+    fn generate_mipmaps(
+        &mut self,
+        device: &Self::Device,
+        queue: &Self::Queue,
+        texture: &Self::Texture,
+    ) -> Result<bool, Self::Error> {
+        let _ = (device, queue, texture);
+        Ok(false)
+    }
+
+    /// Read an area of a texture back into CPU memory.
+    ///
+    /// The pixels should be written into `data` row by row, starting from the top, in the same
+    /// format that they were written into the texture with. This may stall until the GPU has
+    /// finished drawing into the texture.
+    ///
+    /// Returns `Ok(false)` if the backend does not support reading textures, which is the
+    /// default.
+    fn read_texture(&mut self, texture_read: TextureRead<'_, Self>) -> Result<bool, Self::Error> {
+        let _ = texture_read;
+        Ok(false)
+    }
 
     /// Get the maximum texture size.
     fn max_texture_size(&mut self, device: &Self::Device) -> (u32, u32);
 
+    /// Get the features that this backend supports.
+    ///
+    /// This is queried once, when the [`Source`] is created, and the renderer works around any
+    /// missing features. By default, this returns [`Capabilities::default`].
+    ///
+    /// [`Source`]: crate::Source
+    fn capabilities(&mut self, device: &Self::Device) -> Capabilities {
+        let _ = device;
+        Capabilities::default()
+    }
+
     /// Create a new vertex buffer.
     fn create_vertex_buffer(
         &mut self,
         device: &Self::Device,
     ) -> Result<Self::VertexBuffer, Self::Error>;
 
+    /// Delete a vertex buffer.
+    ///
+    /// This is called from [`Source::gpu_flushed`] once the renderer no longer needs the buffer,
+    /// so the commands that used it have finished. By default, the buffer is just dropped.
+    ///
+    /// [`Source::gpu_flushed`]: crate::Source::gpu_flushed
+    fn delete_vertex_buffer(&mut self, buffer: Self::VertexBuffer) {
+        drop(buffer);
+    }
+
     /// Write vertices to a vertex buffer.
     ///
     /// The indices must be valid for the vertices set; however, it is up to the GPU implementation
     /// to actually check this.
+    ///
+    /// The renderer uses a separate buffer for every draw, and only writes into a buffer again
+    /// once [`Source::gpu_flushed`] has been called, or once many buffers have been used since
+    /// the last flush.
+    ///
+    /// [`Source::gpu_flushed`]: crate::Source::gpu_flushed
     fn write_vertices(
         &mut self,
         device: &Self::Device,
@@ -102,9 +184,41 @@ pub trait GpuContext {
         buffer: &Self::VertexBuffer,
         vertices: &[Vertex],
         indices: &[u32],
-    );
+    ) -> Result<(), Self::Error>;
+
+    /// Draw into a texture instead of the surface.
+    ///
+    /// Until this is called again, every [`clear`], [`push_buffers`] and [`capture_area`] should
+    /// target `target` instead of the surface. Passing `None` goes back to drawing on the
+    /// surface. Render targets are ordinary textures that have been created with
+    /// [`create_texture`] and sized with [`write_texture`], and the viewport passed to
+    /// [`push_buffers`] has the same size as the texture.
+    ///
+    /// Pixels should end up in the texture in the same layout that [`write_texture`] uses, with
+    /// the first row at the top, so that the texture can be drawn like any other image. The
+    /// renderer never samples a texture while it is bound as the render target.
+    ///
+    /// Returns `Ok(false)` if the backend does not support render targets, which is the
+    /// default.
+    ///
+    /// [`clear`]: Self::clear
+    /// [`push_buffers`]: Self::push_buffers
+    /// [`capture_area`]: Self::capture_area
+    /// [`create_texture`]: Self::create_texture
+    /// [`write_texture`]: Self::write_texture
+    fn bind_render_target(
+        &mut self,
+        device: &Self::Device,
+        queue: &Self::Queue,
+        target: Option<&Self::Texture>,
+    ) -> Result<bool, Self::Error> {
+        let _ = (device, queue, target);
+        Ok(false)
+    }
 
     /// Capture an area from the screen and put it into a texture.
+    ///
+    /// See [`AreaCapture`] for how the area is defined.
     fn capture_area(&mut self, area_capture: AreaCapture<'_, Self>) -> Result<(), Self::Error>;
 
     /// Push buffer data to the GPU.
@@ -112,6 +226,9 @@ pub trait GpuContext {
     /// The backend is expected to set up a renderer that renders data in `vertex_buffer`,
     /// using `current_texture` to fill the triangles and `mask_texture` to clip them. In addition,
     /// the parameters `transform`, `viewport_size` and `clip` are also expected to be used.
+    ///
+    /// The triangles should be drawn with premultiplied alpha blending, using the blend mode in
+    /// `buffer_push`, as described above.
     fn push_buffers(&mut self, buffer_push: BufferPush<'_, Self>) -> Result<(), Self::Error>;
 }
 
@@ -130,11 +247,17 @@ pub struct TextureWrite<'a, C: GpuContext + ?Sized> {
     pub size: (u32, u32),
 
     /// The format of the image.
+    ///
+    /// This is always [`RgbaPremul`]; images in other formats are converted on the CPU before
+    /// reaching the backend. See [`GpuContext`] for more information.
+    ///
+    /// [`RgbaPremul`]: piet::ImageFormat::RgbaPremul
     pub format: piet::ImageFormat,
 
     /// The data to write.
     ///
-    /// This is `None` if you want to write only zeroes.
+    /// If this is not `None`, it contains exactly `size.0 * size.1 * 4` bytes. This is `None`
+    /// if you want to write only zeroes.
     pub data: Option<&'a [u8]>,
 }
 
@@ -156,12 +279,42 @@ pub struct SubtextureWrite<'a, C: GpuContext + ?Sized> {
     pub size: (u32, u32),
 
     /// The format of the image.
+    ///
+    /// This is always [`RgbaPremul`]; images in other formats are converted on the CPU before
+    /// reaching the backend. See [`GpuContext`] for more information.
+    ///
+    /// [`RgbaPremul`]: piet::ImageFormat::RgbaPremul
     pub format: piet::ImageFormat,
 
     /// The data to write.
+    ///
+    /// This contains exactly `size.0 * size.1 * 4` bytes.
     pub data: &'a [u8],
 }
 
+/// The data necessary to read an area of a texture back into CPU memory.
+pub struct TextureRead<'a, C: GpuContext + ?Sized> {
+    /// The device that the texture lives on.
+    pub device: &'a C::Device,
+
+    /// The queue to push the operation into.
+    pub queue: &'a C::Queue,
+
+    /// The texture to read from.
+    pub texture: &'a C::Texture,
+
+    /// The offset to start reading at.
+    pub offset: (u32, u32),
+
+    /// The size of the area to read.
+    pub size: (u32, u32),
+
+    /// The buffer to read the pixels into.
+    ///
+    /// This contains exactly `size.0 * size.1 * 4` bytes.
+    pub data: &'a mut [u8],
+}
+
 /// The data necessary to capture an area of the screen.
 pub struct AreaCapture<'a, C: GpuContext + ?Sized> {
     /// The device to render onto.
@@ -171,15 +324,23 @@ pub struct AreaCapture<'a, C: GpuContext + ?Sized> {
     pub queue: &'a C::Queue,
 
     /// The texture to write into.
+    ///
+    /// The backend should replace the contents of this texture with an image of exactly
+    /// `size` pixels, in premultiplied RGBA.
     pub texture: &'a C::Texture,
 
     /// The offset to start at.
+    ///
+    /// This is in device pixels, measured from the top-left corner of the render target. The
+    /// captured area always lies entirely within the render target.
     pub offset: (u32, u32),
 
-    /// The size of the rectangle to capture.
+    /// The size of the rectangle to capture, in device pixels.
     pub size: (u32, u32),
 
     /// The current bitmap scale.
+    ///
+    /// This has already been applied to `offset` and `size`.
     pub bitmap_scale: f64,
 }
 
@@ -210,6 +371,18 @@ pub struct BufferPush<'a, C: GpuContext + ?Sized> {
     ///
     /// This is sometimes known as the "scissor rect".
     pub clip: Option<Rect>,
+
+    /// How to blend the triangles onto the render target.
+    pub blend_mode: BlendMode,
+
+    /// The material to shade the triangles with, if any.
+    ///
+    /// If this is set, the backend should shade the triangles with its own implementation of
+    /// the material instead of sampling `current_texture`. The `uv` of each vertex then holds
+    /// the material coordinates of the vertex rather than texture coordinates, and its color
+    /// holds the brush's tint and opacity. The mask, transform, clip and blend mode apply as
+    /// usual. Backends should return an error for materials that they do not recognize.
+    pub material: Option<&'a Material>,
 }
 
 impl<C: GpuContext + ?Sized> GpuContext for &mut C {
@@ -219,6 +392,19 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
     type VertexBuffer = C::VertexBuffer;
     type Error = C::Error;
 
+    fn bind_render_target(
+        &mut self,
+        device: &Self::Device,
+        queue: &Self::Queue,
+        target: Option<&Self::Texture>,
+    ) -> Result<bool, Self::Error> {
+        (**self).bind_render_target(device, queue, target)
+    }
+
+    fn capabilities(&mut self, device: &Self::Device) -> Capabilities {
+        (**self).capabilities(device)
+    }
+
     fn capture_area(&mut self, area_capture: AreaCapture<'_, Self>) -> Result<(), Self::Error> {
         // Convert &C to C
         let AreaCapture {
@@ -260,14 +446,52 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
         (**self).create_vertex_buffer(device)
     }
 
+    fn delete_texture(&mut self, texture: Self::Texture) {
+        (**self).delete_texture(texture)
+    }
+
+    fn delete_vertex_buffer(&mut self, buffer: Self::VertexBuffer) {
+        (**self).delete_vertex_buffer(buffer)
+    }
+
     fn flush(&mut self) -> Result<(), Self::Error> {
         (**self).flush()
     }
 
+    fn generate_mipmaps(
+        &mut self,
+        device: &Self::Device,
+        queue: &Self::Queue,
+        texture: &Self::Texture,
+    ) -> Result<bool, Self::Error> {
+        (**self).generate_mipmaps(device, queue, texture)
+    }
+
     fn max_texture_size(&mut self, device: &Self::Device) -> (u32, u32) {
         (**self).max_texture_size(device)
     }
 
+    fn read_texture(&mut self, texture_read: TextureRead<'_, Self>) -> Result<bool, Self::Error> {
+        // Convert type from &C to C
+        let TextureRead {
+            device,
+            queue,
+            texture,
+            offset,
+            size,
+            data,
+        } = texture_read;
+
+        (**self).read_texture(TextureRead {
+            device,
+            queue,
+            texture,
+            offset,
+            size,
+            data,
+        })
+    }
+
     fn push_buffers(&mut self, buffer_push: BufferPush<'_, Self>) -> Result<(), Self::Error> {
         // C and &C are different types, so make sure to convert.
         let BufferPush {
@@ -279,6 +503,8 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
             transform,
             viewport_size,
             clip,
+            blend_mode,
+            material,
         } = buffer_push;
 
         (**self).push_buffers(BufferPush {
@@ -290,6 +516,8 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
             transform,
             viewport_size,
             clip,
+            blend_mode,
+            material,
         })
     }
 
@@ -298,11 +526,14 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
         device: &Self::Device,
         texture: &Self::Texture,
         interpolation: InterpolationMode,
-    ) {
+    ) -> Result<(), Self::Error> {
         (**self).set_texture_interpolation(device, texture, interpolation)
     }
 
-    fn write_subtexture(&mut self, subtexture_write: SubtextureWrite<'_, Self>) {
+    fn write_subtexture(
+        &mut self,
+        subtexture_write: SubtextureWrite<'_, Self>,
+    ) -> Result<(), Self::Error> {
         // Convert type from &C to C
         let SubtextureWrite {
             device,
@@ -325,7 +556,7 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
         })
     }
 
-    fn write_texture(&mut self, texture_write: TextureWrite<'_, Self>) {
+    fn write_texture(&mut self, texture_write: TextureWrite<'_, Self>) -> Result<(), Self::Error> {
         // Convert type from &C to C
         let TextureWrite {
             device,
@@ -353,11 +584,104 @@ impl<C: GpuContext + ?Sized> GpuContext for &mut C {
         buffer: &Self::VertexBuffer,
         vertices: &[Vertex],
         indices: &[u32],
-    ) {
+    ) -> Result<(), Self::Error> {
         (**self).write_vertices(device, queue, buffer, vertices, indices)
     }
 }
 
+/// The features that a backend supports.
+///
+/// Backends return this from [`GpuContext::capabilities`]. Start from
+/// [`Capabilities::default`], which describes a backend that supports everything the renderer
+/// uses except for advanced blending, and turn off what the backend lacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// The image formats that the backend can write into textures.
+    ///
+    /// The renderer converts every image to [`RgbaPremul`] before uploading it, so this must
+    /// contain it; creating a [`Source`] fails with [`NotSupported`] otherwise. Applications
+    /// can use the other formats to decide how to store their own data.
+    ///
+    /// [`RgbaPremul`]: piet::ImageFormat::RgbaPremul
+    /// [`Source`]: crate::Source
+    /// [`NotSupported`]: piet::Error::NotSupported
+    pub formats: Vec<piet::ImageFormat>,
+
+    /// Whether the backend supports the scissor rectangle in [`BufferPush::clip`].
+    ///
+    /// If this is `false`, the renderer clips to rectangles with a mask instead.
+    pub scissor: bool,
+
+    /// The number of samples per pixel that the render target is multisampled with.
+    ///
+    /// This is `1` if the render target is not multisampled. The renderer always antialiases
+    /// shapes itself, so this is informational.
+    pub msaa_samples: u32,
+
+    /// Whether the backend can draw every separable [`BlendMode`] exactly.
+    ///
+    /// If this is `false`, the renderer replaces the separable blend modes that can't be
+    /// approximated with fixed-function blending with [`BlendMode::SourceOver`]. See
+    /// [`BlendMode`] for details.
+    pub advanced_blending: bool,
+
+    /// Whether the backend supports [`RepeatStrategy::Color`] with a border color.
+    ///
+    /// If this is `false`, the renderer creates textures with [`RepeatStrategy::Clamp`]
+    /// instead, and surrounds images created from pixel data with a border of transparent
+    /// pixels to get the same result.
+    pub border_color: bool,
+
+    /// The maximum number of vertices that can be drawn in one call to
+    /// [`GpuContext::push_buffers`].
+    ///
+    /// The renderer splits larger meshes into several draws. This is always at least three.
+    pub max_vertices: usize,
+
+    /// The identifiers of the [`Material`]s that the backend can shade.
+    ///
+    /// [`RenderContext::material_brush`] refuses to create brushes for any other material, so
+    /// the backend only ever receives these. This is empty by default.
+    ///
+    /// [`RenderContext::material_brush`]: crate::RenderContext::material_brush
+    pub materials: Vec<u32>,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            formats: vec![piet::ImageFormat::RgbaPremul],
+            scissor: true,
+            msaa_samples: 1,
+            advanced_blending: false,
+            border_color: true,
+            max_vertices: usize::MAX,
+            materials: Vec::new(),
+        }
+    }
+}
+
+impl Capabilities {
+    /// Get the repeat strategy for textures that are transparent outside of their bounds.
+    pub(crate) fn transparent_repeat(&self) -> RepeatStrategy {
+        if self.border_color {
+            RepeatStrategy::Color(piet::Color::TRANSPARENT)
+        } else {
+            RepeatStrategy::Clamp
+        }
+    }
+
+    /// Get the blend mode that the backend should be asked to draw with.
+    pub(crate) fn blend_mode(&self, mode: BlendMode) -> BlendMode {
+        match mode {
+            _ if self.advanced_blending || mode.is_porter_duff() => mode,
+            BlendMode::Multiply | BlendMode::Screen | BlendMode::Lighten => mode,
+            _ => BlendMode::SourceOver,
+        }
+    }
+}
+
 /// The strategy to use for repeating.
 #[derive(Debug, Copy, Clone, PartialEq)]
 #[non_exhaustive]
@@ -369,23 +693,211 @@ pub enum RepeatStrategy {
     Clamp,
 
     /// Don't repeat and instead use this color.
+    ///
+    /// This color has separate alpha, so the backend needs to premultiply it to match the
+    /// texture's contents.
     Color(piet::Color),
 }
 
+/// How to blend drawn pixels onto the render target.
+///
+/// Source and destination colors have premultiplied alpha. The Porter-Duff operators can all be
+/// expressed with fixed-function blending, and the table below lists the blend factors for each
+/// of them, in `source, destination` order.
+///
+/// The separable blend modes follow the [W3C compositing specification], and are composited
+/// with source-over. They usually need advanced blending support, such as
+/// `KHR_blend_equation_advanced`. Backends without it should set
+/// [`Capabilities::advanced_blending`] to `false`, in which case the renderer only passes them
+/// [`Multiply`], [`Screen`] and [`Lighten`] out of the separable blend modes. These
+/// should be approximated as described on those variants, and the rest are replaced with
+/// [`SourceOver`]. Use [`BlendMode::is_porter_duff`] to tell the two groups apart.
+///
+/// The renderer applies antialiasing and clip masks by scaling the source's alpha, so it can
+/// only draw with modes that leave the destination alone where the source is transparent.
+/// [`Clear`], [`Source`], [`SourceIn`], [`SourceOut`], [`DestinationIn`], [`DestinationAtop`]
+/// and [`Xor`] would change the destination outside of the clip and along every antialiased
+/// edge, so [`RenderContext::set_blend_mode`] rejects them. Backends never receive those modes
+/// from the renderer.
+///
+/// [W3C compositing specification]: https://www.w3.org/TR/compositing-1/
+/// [`Clear`]: BlendMode::Clear
+/// [`Source`]: BlendMode::Source
+/// [`SourceIn`]: BlendMode::SourceIn
+/// [`SourceOut`]: BlendMode::SourceOut
+/// [`DestinationIn`]: BlendMode::DestinationIn
+/// [`DestinationAtop`]: BlendMode::DestinationAtop
+/// [`Xor`]: BlendMode::Xor
+/// [`RenderContext::set_blend_mode`]: crate::RenderContext::set_blend_mode
+/// [`Multiply`]: BlendMode::Multiply
+/// [`Screen`]: BlendMode::Screen
+/// [`Lighten`]: BlendMode::Lighten
+/// [`SourceOver`]: BlendMode::SourceOver
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum BlendMode {
+    /// Clear the destination: `ZERO, ZERO`.
+    Clear,
+
+    /// Replace the destination with the source: `ONE, ZERO`.
+    Source,
+
+    /// Keep the destination: `ZERO, ONE`.
+    Destination,
+
+    /// Draw the source over the destination: `ONE, ONE_MINUS_SRC_ALPHA`.
+    ///
+    /// This is the default.
+    #[default]
+    SourceOver,
+
+    /// Draw the source behind the destination: `ONE_MINUS_DST_ALPHA, ONE`.
+    DestinationOver,
+
+    /// Keep the source where the destination is: `DST_ALPHA, ZERO`.
+    SourceIn,
+
+    /// Keep the destination where the source is: `ZERO, SRC_ALPHA`.
+    DestinationIn,
+
+    /// Keep the source where the destination isn't: `ONE_MINUS_DST_ALPHA, ZERO`.
+    SourceOut,
+
+    /// Keep the destination where the source isn't: `ZERO, ONE_MINUS_SRC_ALPHA`.
+    DestinationOut,
+
+    /// Draw the source over the destination, only where the destination is:
+    /// `DST_ALPHA, ONE_MINUS_SRC_ALPHA`.
+    SourceAtop,
+
+    /// Draw the destination over the source, only where the source is:
+    /// `ONE_MINUS_DST_ALPHA, SRC_ALPHA`.
+    DestinationAtop,
+
+    /// Keep the source and the destination where they don't overlap:
+    /// `ONE_MINUS_DST_ALPHA, ONE_MINUS_SRC_ALPHA`.
+    Xor,
+
+    /// Add the source to the destination: `ONE, ONE`.
+    Plus,
+
+    /// Multiply the source and destination colors.
+    ///
+    /// This can be approximated with `DST_COLOR, ONE_MINUS_SRC_ALPHA`, which is exact where the
+    /// destination is opaque.
+    Multiply,
+
+    /// Multiply the inverted source and destination colors.
+    ///
+    /// This is exact with `ONE, ONE_MINUS_SRC_COLOR`.
+    Screen,
+
+    /// Multiply or screen the colors, depending on the destination color.
+    Overlay,
+
+    /// Keep the darker of the source and destination colors.
+    ///
+    /// Without advanced blending, this falls back to [`SourceOver`](BlendMode::SourceOver).
+    /// The `MIN` blend equation can't approximate it, since it would darken pixels that the
+    /// source doesn't cover.
+    Darken,
+
+    /// Keep the lighter of the source and destination colors.
+    ///
+    /// This can be approximated with the `MAX` blend equation, which is exact where both colors
+    /// are opaque.
+    Lighten,
+
+    /// Brighten the destination color to reflect the source color.
+    ColorDodge,
+
+    /// Darken the destination color to reflect the source color.
+    ColorBurn,
+
+    /// Multiply or screen the colors, depending on the source color.
+    HardLight,
+
+    /// Darken or lighten the colors, depending on the source color.
+    SoftLight,
+
+    /// Subtract the darker of the two colors from the lighter one.
+    Difference,
+
+    /// Like [`Difference`](BlendMode::Difference), but with lower contrast.
+    Exclusion,
+}
+
+impl BlendMode {
+    /// Tell whether drawing with this blend mode leaves the destination alone where the source
+    /// is transparent.
+    pub(crate) fn preserves_uncovered(self) -> bool {
+        !matches!(
+            self,
+            Self::Clear
+                | Self::Source
+                | Self::SourceIn
+                | Self::SourceOut
+                | Self::DestinationIn
+                | Self::DestinationAtop
+                | Self::Xor
+        )
+    }
+
+    /// Whether this is one of the Porter-Duff operators, which every backend can support with
+    /// fixed-function blending.
+    pub fn is_porter_duff(self) -> bool {
+        matches!(
+            self,
+            Self::Clear
+                | Self::Source
+                | Self::Destination
+                | Self::SourceOver
+                | Self::DestinationOver
+                | Self::SourceIn
+                | Self::DestinationIn
+                | Self::SourceOut
+                | Self::DestinationOut
+                | Self::SourceAtop
+                | Self::DestinationAtop
+                | Self::Xor
+                | Self::Plus
+        )
+    }
+}
+
 /// The vertex type used by the GPU renderer.
-#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Default, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd, bytemuck::Pod, bytemuck::Zeroable)]
 #[repr(C)]
 pub struct Vertex {
     /// The position of the vertex.
     pub pos: [f32; 2],
 
-    /// The coordinate of the vertex in the texture.
+    /// The coordinate of the vertex in the texture, multiplied by `q`.
     pub uv: [f32; 2],
 
+    /// The homogeneous texture coordinate.
+    ///
+    /// Backends should interpolate `uv` and `q` linearly across each triangle and sample the
+    /// texture at `uv / q`, which keeps images that are mapped in perspective from warping. This
+    /// is `1.0` everywhere except for perspective-mapped images. It directly follows `uv`, so
+    /// both can be read as a single three-component attribute.
+    pub q: f32,
+
     /// The color of the vertex, in four SRGB channels.
     pub color: [u8; 4],
 }
 
+impl Default for Vertex {
+    fn default() -> Self {
+        Self {
+            pos: [0.0; 2],
+            uv: [0.0; 2],
+            q: 1.0,
+            color: [0; 4],
+        }
+    }
+}
+
 /// The type of the buffer to use.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum BufferType {
@@ -0,0 +1,460 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Gradient options and caching for gradient textures.
+
+use super::brush::Brush;
+use super::gpu_backend::GpuContext;
+
+use ahash::RandomState;
+use hashbrown::HashMap;
+
+use piet::kurbo::Point;
+use piet::{Error as Pierror, FixedGradient, GradientStop};
+
+/// The default number of gradients to keep around.
+const DEFAULT_CAPACITY: usize = 64;
+
+/// The number of stops to insert between each pair of user-provided stops when interpolating
+/// in a color space other than sRGB.
+const INTERPOLATION_STEPS: usize = 16;
+
+/// The minimum number of pixels that a single 8-bit color step has to cover before banding
+/// becomes visible enough that [`Dithering::Auto`] dithers the gradient.
+const AUTO_DITHER_PIXELS_PER_STEP: f64 = 3.0;
+
+/// An 8x8 Bayer matrix used for ordered dithering.
+const BAYER_MATRIX: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Options for creating a gradient brush.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub struct GradientOptions {
+    /// The color space that the gradient's stops are interpolated in.
+    pub interpolation: ColorInterpolation,
+
+    /// Whether or not to dither the gradient when rasterizing it.
+    pub dithering: Dithering,
+}
+
+/// The color space that gradient stops are interpolated in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum ColorInterpolation {
+    /// Interpolate the sRGB-encoded color channels directly.
+    ///
+    /// This is what most other `piet` backends do, but it can produce muddy midpoints.
+    #[default]
+    Srgb,
+
+    /// Interpolate in linear-light sRGB.
+    LinearSrgb,
+
+    /// Interpolate in the perceptually uniform OkLab color space.
+    Oklab,
+}
+
+/// Whether or not to dither a gradient to hide banding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum Dithering {
+    /// Dither the gradient if its colors change slowly enough for banding to be visible.
+    #[default]
+    Auto,
+
+    /// Always dither the gradient.
+    Always,
+
+    /// Never dither the gradient.
+    Never,
+}
+
+impl Dithering {
+    /// Tell whether a gradient with these stops, spanning `length` pixels, should be dithered.
+    pub(crate) fn should_dither(self, stops: &[GradientStop], length: f64) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto => stops.windows(2).any(|pair| {
+                let (start, end) = (pair[0].color.as_rgba8(), pair[1].color.as_rgba8());
+                let steps = [
+                    start.0.abs_diff(end.0),
+                    start.1.abs_diff(end.1),
+                    start.2.abs_diff(end.2),
+                    start.3.abs_diff(end.3),
+                ]
+                .into_iter()
+                .max()
+                .unwrap_or(0);
+
+                let pixels = length * (pair[1].pos - pair[0].pos).abs() as f64;
+                steps > 0 && pixels / steps as f64 >= AUTO_DITHER_PIXELS_PER_STEP
+            }),
+        }
+    }
+}
+
+/// A cache of gradient brushes, keyed by the description of the gradient.
+///
+/// Rasterizing a gradient involves allocating a texture and rendering into it on the CPU, so
+/// re-using the texture for identical gradients saves a lot of work.
+pub(crate) struct GradientCache<C: GpuContext + ?Sized> {
+    /// The cached gradients.
+    entries: HashMap<GradientKey, CacheEntry<C>, RandomState>,
+
+    /// The maximum number of gradients to keep around.
+    capacity: usize,
+
+    /// Incremented every time the cache is accessed.
+    ///
+    /// This is used to find the least recently used entry.
+    clock: u64,
+}
+
+struct CacheEntry<C: GpuContext + ?Sized> {
+    /// The brush containing the gradient texture.
+    brush: Brush<C>,
+
+    /// The last time this entry was used.
+    last_used: u64,
+}
+
+/// A hashable description of a gradient.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct GradientKey {
+    /// The geometry of the gradient, as raw floating point bits.
+    geometry: Geometry,
+
+    /// The stops of the gradient, as the position bits and the RGBA color.
+    stops: Vec<(u32, u32)>,
+
+    /// The options used to create the gradient.
+    options: GradientOptions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Geometry {
+    /// A linear gradient, with a start and end point.
+    Linear { start: [u64; 2], end: [u64; 2] },
+
+    /// A radial gradient, with a center, origin offset and radius.
+    Radial {
+        center: [u64; 2],
+        origin_offset: [u64; 2],
+        radius: u64,
+    },
+}
+
+impl GradientKey {
+    fn new(gradient: &FixedGradient, options: GradientOptions) -> Self {
+        let point = |pt: Point| [pt.x.to_bits(), pt.y.to_bits()];
+        let stops = |stops: &[GradientStop]| {
+            stops
+                .iter()
+                .map(|stop| (stop.pos.to_bits(), stop.color.as_rgba_u32()))
+                .collect()
+        };
+
+        match gradient {
+            FixedGradient::Linear(linear) => Self {
+                geometry: Geometry::Linear {
+                    start: point(linear.start),
+                    end: point(linear.end),
+                },
+                stops: stops(&linear.stops),
+                options,
+            },
+            FixedGradient::Radial(radial) => Self {
+                geometry: Geometry::Radial {
+                    center: point(radial.center),
+                    origin_offset: [
+                        radial.origin_offset.x.to_bits(),
+                        radial.origin_offset.y.to_bits(),
+                    ],
+                    radius: radial.radius.to_bits(),
+                },
+                stops: stops(&radial.stops),
+                options,
+            },
+        }
+    }
+}
+
+impl<C: GpuContext + ?Sized> GradientCache<C> {
+    /// Create a new, empty gradient cache.
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: HashMap::with_hasher(RandomState::new()),
+            capacity: DEFAULT_CAPACITY,
+            clock: 0,
+        }
+    }
+
+    /// Set the maximum number of gradients to keep around.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > self.capacity {
+            self.evict();
+        }
+    }
+
+    /// Get the maximum number of gradients to keep around.
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Remove all gradients from the cache.
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Get the brush for a gradient, or create it using `create` if it isn't cached.
+    pub(crate) fn get_or_insert_with(
+        &mut self,
+        gradient: &FixedGradient,
+        options: GradientOptions,
+        create: impl FnOnce() -> Result<Brush<C>, Pierror>,
+    ) -> Result<Brush<C>, Pierror> {
+        self.clock += 1;
+        let key = GradientKey::new(gradient, options);
+
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.last_used = self.clock;
+            return Ok(entry.brush.clone());
+        }
+
+        let brush = create()?;
+        if self.capacity == 0 {
+            return Ok(brush);
+        }
+
+        // Make room for the new entry.
+        while self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                brush: brush.clone(),
+                last_used: self.clock,
+            },
+        );
+
+        Ok(brush)
+    }
+
+    /// Remove the least recently used entry.
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/// Rasterize a gradient into premultiplied RGBA pixels with ordered dithering applied.
+///
+/// `position` maps the center of a pixel to the position along the gradient, or `None` if the
+/// gradient is not defined at that point.
+pub(crate) fn rasterize_dithered(
+    (width, height): (u32, u32),
+    stops: &[GradientStop],
+    position: impl Fn(f64, f64) -> Option<f64>,
+) -> Vec<u8> {
+    let mut data = Vec::with_capacity(width as usize * height as usize * 4);
+
+    for y in 0..height {
+        for x in 0..width {
+            let color = match position(x as f64 + 0.5, y as f64 + 0.5) {
+                Some(t) => sample_stops(stops, t.clamp(0.0, 1.0)),
+                None => [0.0; 4],
+            };
+
+            // Premultiply, then offset by the threshold before quantizing.
+            let threshold =
+                (BAYER_MATRIX[y as usize % 8][x as usize % 8] as f64 + 0.5) / 64.0 - 0.5;
+            let alpha = color[3];
+            let quantize = |c: f64| (c * 255.0 + threshold).round().clamp(0.0, 255.0) as u8;
+
+            let alpha_byte = quantize(alpha);
+            data.extend(
+                [color[0] * alpha, color[1] * alpha, color[2] * alpha]
+                    .map(|c| quantize(c).min(alpha_byte)),
+            );
+            data.push(alpha_byte);
+        }
+    }
+
+    data
+}
+
+/// Get the un-premultiplied color at position `t` along the gradient.
+fn sample_stops(stops: &[GradientStop], t: f64) -> [f64; 4] {
+    let components = |color: piet::Color| {
+        let (r, g, b, a) = color.as_rgba();
+        [r, g, b, a]
+    };
+
+    let next = stops.iter().position(|stop| stop.pos as f64 > t);
+    match next {
+        None => stops.last().map_or([0.0; 4], |stop| components(stop.color)),
+        Some(0) => components(stops[0].color),
+        Some(i) => {
+            let (start, end) = (&stops[i - 1], &stops[i]);
+            let span = (end.pos - start.pos) as f64;
+            let t = if span > 0.0 {
+                (t - start.pos as f64) / span
+            } else {
+                0.0
+            };
+
+            let (start, end) = (components(start.color), components(end.color));
+            let mut mixed = [0.0; 4];
+            for (i, channel) in mixed.iter_mut().enumerate() {
+                *channel = start[i] + (end[i] - start[i]) * t;
+            }
+            mixed
+        }
+    }
+}
+
+/// A conversion between two color spaces.
+type Conversion = fn([f64; 3]) -> [f64; 3];
+
+/// Resample a list of gradient stops so that interpolating them in sRGB approximates
+/// interpolating the original stops in another color space.
+pub(crate) fn interpolate_stops(stops: &mut Vec<GradientStop>, interpolation: ColorInterpolation) {
+    let (to_space, from_space): (Conversion, Conversion) = match interpolation {
+        ColorInterpolation::Srgb => return,
+        ColorInterpolation::LinearSrgb => (srgb_to_linear, linear_to_srgb),
+        ColorInterpolation::Oklab => (
+            |rgb| linear_to_oklab(srgb_to_linear(rgb)),
+            |lab| linear_to_srgb(oklab_to_linear(lab)),
+        ),
+    };
+
+    // Convert a color into premultiplied components in the target space.
+    let convert = |color: piet::Color| {
+        let (r, g, b, a) = color.as_rgba();
+        let [x, y, z] = to_space([r, g, b]);
+        [x * a, y * a, z * a, a]
+    };
+
+    let mut resampled = Vec::with_capacity(stops.len() * INTERPOLATION_STEPS);
+    for pair in stops.windows(2) {
+        let (start, end) = (&pair[0], &pair[1]);
+        resampled.push(start.clone());
+
+        if start.color == end.color {
+            continue;
+        }
+
+        let (start_color, end_color) = (convert(start.color), convert(end.color));
+        for step in 1..INTERPOLATION_STEPS {
+            let t = step as f64 / INTERPOLATION_STEPS as f64;
+            let mut mixed = [0.0; 4];
+            for (i, channel) in mixed.iter_mut().enumerate() {
+                *channel = start_color[i] + (end_color[i] - start_color[i]) * t;
+            }
+
+            // Un-premultiply and convert back into sRGB.
+            let alpha = mixed[3];
+            let [r, g, b] = if alpha > 0.0 {
+                from_space([mixed[0] / alpha, mixed[1] / alpha, mixed[2] / alpha])
+            } else {
+                [0.0; 3]
+            };
+
+            resampled.push(GradientStop {
+                pos: start.pos + (end.pos - start.pos) * t as f32,
+                color: piet::Color::rgba(
+                    r.clamp(0.0, 1.0),
+                    g.clamp(0.0, 1.0),
+                    b.clamp(0.0, 1.0),
+                    alpha.clamp(0.0, 1.0),
+                ),
+            });
+        }
+    }
+    resampled.extend(stops.last().cloned());
+
+    *stops = resampled;
+}
+
+fn srgb_to_linear(rgb: [f64; 3]) -> [f64; 3] {
+    rgb.map(|c| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    })
+}
+
+fn linear_to_srgb(rgb: [f64; 3]) -> [f64; 3] {
+    rgb.map(|c| {
+        if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    })
+}
+
+fn linear_to_oklab([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let l = 0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b;
+    let m = 0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b;
+    let s = 0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b;
+
+    let (l, m, s) = (l.cbrt(), m.cbrt(), s.cbrt());
+
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let l_ = l + 0.3963377774 * a + 0.2158037573 * b;
+    let m_ = l - 0.1055613458 * a - 0.0638541728 * b;
+    let s_ = l - 0.0894841775 * a - 1.2914855480 * b;
+
+    let (l, m, s) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960771 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
@@ -19,10 +19,13 @@
 //! The image type for the GPU renderer.
 
 use super::gpu_backend::GpuContext;
+use super::image_atlas::AtlasSlot;
 use super::resources::Texture;
 
-use piet::kurbo::Size;
+use piet::kurbo::{Point, Rect, Size};
+use piet::{Error as Pierror, ImageFormat};
 
+use std::borrow::Cow;
 use std::rc::Rc;
 
 /// The image type used by the GPU renderer.
@@ -33,14 +36,89 @@ pub struct Image<C: GpuContext + ?Sized> {
 
     /// The size of the image.
     size: Size,
+
+    /// The area of the texture that this image occupies, in UV coordinates.
+    uv_rect: Rect,
+
+    /// The offset of the image in the texture, in pixels.
+    offset: (u32, u32),
+
+    /// The size of the image in the texture, in pixels.
+    pixel_size: (u32, u32),
+
+    /// The format of the pixels that the image was created from.
+    ///
+    /// The texture itself always holds premultiplied RGBA pixels.
+    format: piet::ImageFormat,
+
+    /// The slot in the image atlas that this image occupies, if any.
+    slot: Option<Rc<AtlasSlot<C>>>,
 }
 
 impl<C: GpuContext + ?Sized> Image<C> {
     /// Create a new image from a texture.
-    pub(crate) fn new(texture: Texture<C>, size: Size) -> Self {
+    pub(crate) fn new(
+        texture: Texture<C>,
+        size: Size,
+        pixel_size: (u32, u32),
+        format: piet::ImageFormat,
+    ) -> Self {
+        Self {
+            texture: Rc::new(texture),
+            size,
+            uv_rect: Rect::new(0.0, 0.0, 1.0, 1.0),
+            offset: (0, 0),
+            pixel_size,
+            format,
+            slot: None,
+        }
+    }
+
+    /// Create a new image from a texture that surrounds it with `padding` transparent pixels.
+    pub(crate) fn padded(
+        texture: Texture<C>,
+        size: Size,
+        pixel_size: (u32, u32),
+        format: piet::ImageFormat,
+        padding: u32,
+    ) -> Self {
+        let texture_width = (pixel_size.0 + padding * 2) as f64;
+        let texture_height = (pixel_size.1 + padding * 2) as f64;
+        let padding_f = padding as f64;
+
         Self {
             texture: Rc::new(texture),
             size,
+            uv_rect: Rect::new(
+                padding_f / texture_width,
+                padding_f / texture_height,
+                (padding_f + pixel_size.0 as f64) / texture_width,
+                (padding_f + pixel_size.1 as f64) / texture_height,
+            ),
+            offset: (padding, padding),
+            pixel_size,
+            format,
+            slot: None,
+        }
+    }
+
+    /// Create a new image from a slot in the image atlas.
+    pub(crate) fn from_atlas(
+        texture: Rc<Texture<C>>,
+        uv_rect: Rect,
+        offset: (u32, u32),
+        pixel_size: (u32, u32),
+        format: piet::ImageFormat,
+        slot: AtlasSlot<C>,
+    ) -> Self {
+        Self {
+            texture,
+            size: Size::new(pixel_size.0 as f64, pixel_size.1 as f64),
+            uv_rect,
+            offset,
+            pixel_size,
+            format,
+            slot: Some(Rc::new(slot)),
         }
     }
 
@@ -48,6 +126,52 @@ impl<C: GpuContext + ?Sized> Image<C> {
     pub(crate) fn texture(&self) -> &Texture<C> {
         &self.texture
     }
+
+    /// Get the offset of the image in the texture, in pixels.
+    pub(crate) fn offset(&self) -> (u32, u32) {
+        self.offset
+    }
+
+    /// Get the size of the image in the texture, in pixels.
+    pub(crate) fn pixel_size(&self) -> (u32, u32) {
+        self.pixel_size
+    }
+
+    /// Get the format of the pixels that the image was created from.
+    pub(crate) fn format(&self) -> piet::ImageFormat {
+        self.format
+    }
+
+    /// Set the format of the pixels that the image was created from.
+    pub(crate) fn with_format(mut self, format: piet::ImageFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Tell whether this image shares its texture with other images.
+    pub(crate) fn is_atlased(&self) -> bool {
+        self.slot.is_some()
+    }
+
+    /// Get the slot in the image atlas that this image occupies, if any.
+    pub(crate) fn atlas_slot(&self) -> Option<&AtlasSlot<C>> {
+        self.slot.as_deref()
+    }
+
+    /// Map a point in the image's UV space to the texture's UV space.
+    pub(crate) fn map_uv(&self, point: Point) -> Point {
+        let Rect { x0, y0, x1, y1 } = self.uv_rect;
+        Point::new(x0 + point.x * (x1 - x0), y0 + point.y * (y1 - y0))
+    }
+
+    /// Map a rectangle in the image's UV space to the texture's UV space.
+    pub(crate) fn map_uv_rect(&self, rect: Rect) -> Rect {
+        let (start, end) = (
+            self.map_uv(rect.origin()),
+            self.map_uv((rect.x1, rect.y1).into()),
+        );
+        Rect::new(start.x, start.y, end.x, end.y)
+    }
 }
 
 impl<C: GpuContext + ?Sized> Clone for Image<C> {
@@ -55,6 +179,11 @@ impl<C: GpuContext + ?Sized> Clone for Image<C> {
         Self {
             texture: self.texture.clone(),
             size: self.size,
+            uv_rect: self.uv_rect,
+            offset: self.offset,
+            pixel_size: self.pixel_size,
+            format: self.format,
+            slot: self.slot.clone(),
         }
     }
 }
@@ -64,3 +193,91 @@ impl<C: GpuContext + ?Sized> piet::Image for Image<C> {
         self.size
     }
 }
+
+/// Image data converted into a format that can be handed to the backend, along with its size.
+type NormalizedImage<'a> = (Cow<'a, [u8]>, (u32, u32), ImageFormat);
+
+/// Check that an image buffer has the right size and convert it into a four-channel format.
+///
+/// Backends are only ever given [`ImageFormat::RgbaPremul`] data, so that textures are always
+/// filtered with premultiplied alpha. Grayscale and RGB images are expanded into opaque RGBA
+/// images, and images with separate alpha are premultiplied.
+pub(crate) fn normalize_image_data(
+    (width, height): (usize, usize),
+    buf: &[u8],
+    format: ImageFormat,
+) -> Result<NormalizedImage<'_>, Pierror> {
+    // Make sure that the buffer is exactly the right size.
+    let size = (
+        u32::try_from(width).map_err(|_| Pierror::InvalidInput)?,
+        u32::try_from(height).map_err(|_| Pierror::InvalidInput)?,
+    );
+    let expected = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(format.bytes_per_pixel()))
+        .ok_or(Pierror::InvalidInput)?;
+    if buf.len() != expected {
+        tracing::error!(
+            "image buffer has {} bytes, expected {} for a {}x{} {:?} image",
+            buf.len(),
+            expected,
+            width,
+            height,
+            format
+        );
+        return Err(Pierror::InvalidInput);
+    }
+
+    let (data, format) = match format {
+        ImageFormat::RgbaPremul => (Cow::Borrowed(buf), format),
+        ImageFormat::RgbaSeparate => (
+            Cow::Owned(
+                buf.chunks_exact(4)
+                    .flat_map(|rgba| {
+                        let alpha = rgba[3];
+                        [
+                            premultiply(rgba[0], alpha),
+                            premultiply(rgba[1], alpha),
+                            premultiply(rgba[2], alpha),
+                            alpha,
+                        ]
+                    })
+                    .collect(),
+            ),
+            ImageFormat::RgbaPremul,
+        ),
+        ImageFormat::Grayscale => (
+            Cow::Owned(buf.iter().flat_map(|&v| [v, v, v, 0xFF]).collect()),
+            ImageFormat::RgbaPremul,
+        ),
+        ImageFormat::Rgb => (
+            Cow::Owned(
+                buf.chunks_exact(3)
+                    .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 0xFF])
+                    .collect(),
+            ),
+            ImageFormat::RgbaPremul,
+        ),
+        _ => return Err(Pierror::NotSupported),
+    };
+
+    Ok((data, size, format))
+}
+
+/// Surround four-channel image data with `padding` transparent pixels on every side.
+pub(crate) fn pad_image(data: &[u8], (width, height): (u32, u32), padding: u32) -> Vec<u8> {
+    let stride = width as usize * 4;
+    let padded_stride = (width + padding * 2) as usize * 4;
+    let mut buffer = vec![0u8; padded_stride * (height + padding * 2) as usize];
+    for (row, src) in data.chunks_exact(stride).take(height as usize).enumerate() {
+        let start = (row + padding as usize) * padded_stride + padding as usize * 4;
+        buffer[start..start + stride].copy_from_slice(src);
+    }
+
+    buffer
+}
+
+/// Multiply a color channel by an alpha value, rounding to the nearest integer.
+pub(crate) fn premultiply(channel: u8, alpha: u8) -> u8 {
+    ((channel as u16 * alpha as u16 + 127) / 255) as u8
+}
@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! The image atlas, which packs small images into shared textures.
+
+use super::gpu_backend::{GpuContext, RepeatStrategy};
+use super::image::{self, Image};
+use super::resources::{DeletionQueue, Texture};
+use super::ResultExt;
+
+use etagere::{AllocId, AtlasAllocator};
+
+use piet::kurbo::Rect;
+use piet::{Error as Pierror, InterpolationMode};
+
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+/// The preferred size of a single page in the atlas.
+const PAGE_SIZE: u32 = 1024;
+
+/// The number of transparent pixels to leave around each image.
+///
+/// This keeps bilinear filtering from bleeding neighboring images into each other.
+const PADDING: u32 = 1;
+
+/// An atlas that packs small images into a set of shared textures.
+pub(crate) struct ImageAtlas<C: GpuContext + ?Sized> {
+    /// The pages of the atlas.
+    ///
+    /// Pages are dropped once they become empty, leaving a `None` in their place so that the
+    /// indices of the other pages stay the same.
+    pages: Vec<Option<Page<C>>>,
+
+    /// Images with a width and height at or below this size are packed into the atlas.
+    ///
+    /// If this is `None`, the atlas is disabled.
+    threshold: Option<u32>,
+
+    /// Allocations that have been freed by their images and need to be returned to the pages.
+    ///
+    /// These are only returned once the GPU is done with them, since a queued draw may still
+    /// sample the freed image.
+    freed: Rc<RefCell<Vec<(usize, AllocId)>>>,
+
+    /// The repeat strategy to create pages with.
+    repeat: RepeatStrategy,
+}
+
+/// A single texture in the atlas.
+struct Page<C: GpuContext + ?Sized> {
+    /// The texture backing this page.
+    texture: Rc<Texture<C>>,
+
+    /// The size of the texture.
+    size: (u32, u32),
+
+    /// The allocator for this page.
+    allocator: AtlasAllocator,
+}
+
+/// The slot that an image occupies in the atlas.
+///
+/// When this is dropped, the slot is returned to the atlas after the next GPU flush.
+pub(crate) struct AtlasSlot<C: GpuContext + ?Sized> {
+    /// The index of the page that this slot is in.
+    page: usize,
+
+    /// The allocation in that page.
+    id: AllocId,
+
+    /// The list to push this slot into once it is freed.
+    freed: Rc<RefCell<Vec<(usize, AllocId)>>>,
+
+    /// The width of the image, in pixels.
+    width: u32,
+
+    /// A copy of the image's pixels, used to copy the image out of the atlas.
+    data: RefCell<Vec<u8>>,
+
+    /// A copy of the image in its own texture, if one has been made.
+    detached: RefCell<Option<Image<C>>>,
+}
+
+impl<C: GpuContext + ?Sized> AtlasSlot<C> {
+    /// Get the copy of the image's pixels.
+    pub(crate) fn data(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.data.borrow(), |data| &data[..])
+    }
+
+    /// Write new pixels into an area of the image's copy.
+    ///
+    /// This throws away the detached copy of the image, since it is now out of date.
+    pub(crate) fn write_area(&self, (x, y): (u32, u32), (width, _): (u32, u32), data: &[u8]) {
+        let mut pixels = self.data.borrow_mut();
+        let stride = self.width as usize * 4;
+        let row_len = width as usize * 4;
+
+        for (row, src) in data.chunks_exact(row_len).enumerate() {
+            let start = (y as usize + row) * stride + x as usize * 4;
+            pixels[start..start + row_len].copy_from_slice(src);
+        }
+
+        self.detached.borrow_mut().take();
+    }
+
+    /// Get a copy of the image in its own texture, creating it with `create` if needed.
+    pub(crate) fn detached(
+        &self,
+        create: impl FnOnce(&Self) -> Result<Image<C>, Pierror>,
+    ) -> Result<Image<C>, Pierror> {
+        if let Some(image) = &*self.detached.borrow() {
+            return Ok(image.clone());
+        }
+
+        let image = create(self)?;
+        *self.detached.borrow_mut() = Some(image.clone());
+        Ok(image)
+    }
+}
+
+impl<C: GpuContext + ?Sized> Drop for AtlasSlot<C> {
+    fn drop(&mut self) {
+        self.freed.borrow_mut().push((self.page, self.id));
+    }
+}
+
+impl<C: GpuContext + ?Sized> fmt::Debug for AtlasSlot<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtlasSlot")
+            .field("page", &self.page)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<C: GpuContext + ?Sized> ImageAtlas<C> {
+    /// Create a new, disabled image atlas whose pages use the given repeat strategy.
+    pub(crate) fn new(repeat: RepeatStrategy) -> Self {
+        Self {
+            pages: Vec::new(),
+            threshold: None,
+            freed: Rc::new(RefCell::new(Vec::new())),
+            repeat,
+        }
+    }
+
+    /// Get the maximum size of images packed into the atlas.
+    pub(crate) fn threshold(&self) -> Option<u32> {
+        self.threshold
+    }
+
+    /// Set the maximum size of images packed into the atlas.
+    pub(crate) fn set_threshold(&mut self, threshold: Option<u32>) {
+        self.threshold = threshold;
+    }
+
+    /// Drop the atlas's references to its pages.
+    ///
+    /// Pages that are still used by images are kept alive by those images.
+    pub(crate) fn clear(&mut self) {
+        self.pages.clear();
+        self.freed.borrow_mut().clear();
+    }
+
+    /// Return the slots freed before the GPU was flushed to their pages.
+    ///
+    /// Pages that no longer hold any images are dropped.
+    pub(crate) fn gpu_flushed(&mut self) {
+        for (index, id) in self.freed.borrow_mut().drain(..) {
+            let slot = &mut self.pages[index];
+            if let Some(page) = slot {
+                page.allocator.deallocate(id);
+                if page.allocator.is_empty() {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    /// Try to pack an image into the atlas.
+    ///
+    /// Returns `None` if the image is not suitable for the atlas.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn allocate(
+        &mut self,
+        context: &mut C,
+        device: &C::Device,
+        queue: &C::Queue,
+        deletions: &DeletionQueue<C>,
+        (width, height): (u32, u32),
+        format: piet::ImageFormat,
+        data: &[u8],
+    ) -> Result<Option<Image<C>>, Pierror> {
+        // Only pack small, four-channel images.
+        match self.threshold {
+            Some(threshold) if width <= threshold && height <= threshold => {}
+            _ => return Ok(None),
+        }
+        if width == 0 || height == 0 || format.bytes_per_pixel() != 4 {
+            return Ok(None);
+        }
+
+        let padded = (width + PADDING * 2, height + PADDING * 2);
+        let request = [padded.0 as i32, padded.1 as i32].into();
+
+        // Look for a page with enough room, or make a new one.
+        let found = self
+            .pages
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, page)| page.as_mut().map(|page| (i, page)))
+            .find_map(|(i, page)| page.allocator.allocate(request).map(|alloc| (i, alloc)));
+        let (page_index, alloc) = match found {
+            Some(found) => found,
+            None => {
+                let (max_width, max_height) = context.max_texture_size(device);
+                let size = (PAGE_SIZE.min(max_width), PAGE_SIZE.min(max_height));
+                if padded.0 > size.0 || padded.1 > size.1 {
+                    return Ok(None);
+                }
+
+                let texture = Texture::new(
+                    context,
+                    device,
+                    deletions,
+                    InterpolationMode::Bilinear,
+                    self.repeat,
+                )
+                .piet_err()?;
+                texture
+                    .write_texture(
+                        context,
+                        device,
+                        queue,
+                        size,
+                        piet::ImageFormat::RgbaPremul,
+                        None,
+                    )
+                    .piet_err()?;
+
+                let mut page = Page {
+                    texture: Rc::new(texture),
+                    size,
+                    allocator: AtlasAllocator::new([size.0 as i32, size.1 as i32].into()),
+                };
+                let alloc = page.allocator.allocate(request).ok_or_else(|| {
+                    Pierror::BackendError("Failed to allocate image in image atlas.".into())
+                })?;
+
+                // Put the page in the place of a dropped one, if there is one.
+                let index = match self.pages.iter().position(Option::is_none) {
+                    Some(index) => index,
+                    None => {
+                        self.pages.push(None);
+                        self.pages.len() - 1
+                    }
+                };
+                self.pages[index] = Some(page);
+                (index, alloc)
+            }
+        };
+        let page = self.pages[page_index]
+            .as_mut()
+            .expect("allocated in a missing page");
+
+        // Surround the image with transparent padding, since the slot may have been used before.
+        let buffer = image::pad_image(data, (width, height), PADDING);
+
+        let origin = (alloc.rectangle.min.x as u32, alloc.rectangle.min.y as u32);
+        let written = page
+            .texture
+            .write_subtexture(context, device, queue, origin, padded, format, &buffer)
+            .piet_err();
+        if written.is_err() {
+            page.allocator.deallocate(alloc.id);
+        }
+        written?;
+
+        // Figure out where the image lives in UV space.
+        let (page_width, page_height) = (page.size.0 as f64, page.size.1 as f64);
+        let x0 = (origin.0 + PADDING) as f64;
+        let y0 = (origin.1 + PADDING) as f64;
+        let uv_rect = Rect::new(
+            x0 / page_width,
+            y0 / page_height,
+            (x0 + width as f64) / page_width,
+            (y0 + height as f64) / page_height,
+        );
+
+        let slot = AtlasSlot {
+            page: page_index,
+            id: alloc.id,
+            freed: self.freed.clone(),
+            width,
+            data: RefCell::new(data.to_vec()),
+            detached: RefCell::new(None),
+        };
+
+        Ok(Some(Image::from_atlas(
+            page.texture.clone(),
+            uv_rect,
+            (origin.0 + PADDING, origin.1 + PADDING),
+            (width, height),
+            format,
+            slot,
+        )))
+    }
+}
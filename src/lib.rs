@@ -44,41 +44,62 @@ pub use piet;
 
 use lyon_tessellation::FillRule;
 
-use piet::kurbo::{Affine, PathEl, Point, Rect, Shape, Size};
+use piet::kurbo::{Affine, Insets, PathEl, Point, Rect, Shape, Size};
 use piet::{Error as Pierror, FixedGradient, Image as _, InterpolationMode};
 
 use piet_cosmic_text::LineProcessor;
 use tinyvec::TinyVec;
 
+use std::borrow::Cow;
 use std::error::Error as StdError;
 use std::fmt;
 use std::mem;
 
 mod atlas;
 mod brush;
+#[cfg(feature = "image-decoding")]
+mod decode;
 mod gpu_backend;
+mod gradient;
 mod image;
+mod image_atlas;
 mod mask;
+mod material;
+mod nine_slice;
+mod quad;
 mod rasterizer;
+mod readback;
 mod resources;
 mod stroke;
 mod text;
 
 pub use self::brush::Brush;
-pub use self::gpu_backend::{BufferType, GpuContext, RepeatStrategy, Vertex};
+pub use self::gpu_backend::{
+    BlendMode, BufferType, Capabilities, GpuContext, RepeatStrategy, Vertex,
+};
+pub use self::gradient::{ColorInterpolation, Dithering, GradientOptions};
 pub use self::image::Image;
+pub use self::material::Material;
+pub use self::nine_slice::NineSliceMode;
+pub use self::quad::QuadMapping;
+pub use self::readback::ImageReadback;
 pub use self::text::{Text, TextLayout, TextLayoutBuilder};
 
 pub(crate) use atlas::{Atlas, GlyphData};
+pub(crate) use gradient::GradientCache;
+pub(crate) use image_atlas::ImageAtlas;
 pub(crate) use mask::{Mask, MaskContext};
 pub(crate) use rasterizer::{Rasterizer, TessRect};
-pub(crate) use resources::{Texture, VertexBuffer};
+pub(crate) use readback::PendingReadback;
+pub(crate) use resources::{DeletionQueue, Texture, VertexBufferPool};
 
 const UV_WHITE: [f32; 2] = [0.5, 0.5];
 
 /// Structures that are useful for implementing the `GpuContext` type.
 pub mod gpu_types {
-    pub use crate::gpu_backend::{AreaCapture, BufferPush, SubtextureWrite, TextureWrite};
+    pub use crate::gpu_backend::{
+        AreaCapture, BufferPush, SubtextureWrite, TextureRead, TextureWrite,
+    };
 }
 
 /// The source of the GPU renderer.
@@ -101,6 +122,32 @@ pub struct Source<C: GpuContext + ?Sized> {
     /// The mask rendering context.
     mask_context: MaskContext<C>,
 
+    /// The cache of gradient textures.
+    gradient_cache: GradientCache<C>,
+
+    /// The atlas for packing small images together.
+    image_atlas: ImageAtlas<C>,
+
+    /// Image readbacks waiting for the GPU to be flushed.
+    pending_readbacks: Vec<PendingReadback<C>>,
+
+    /// Resources that have been dropped and are waiting to be deleted.
+    deletions: DeletionQueue<C>,
+
+    /// Offscreen images that layers have finished with, kept around for reuse.
+    layer_images: Vec<Image<C>>,
+
+    /// Offscreen images that layers have finished with since the GPU was last flushed.
+    ///
+    /// Queued draws may still sample these, so they can't be reused until the next flush.
+    used_layer_images: Vec<Image<C>>,
+
+    /// The features that the backend supports.
+    capabilities: Capabilities,
+
+    /// The size of the render target, as of the last call to `resize`.
+    size: Option<(u32, u32)>,
+
     /// The cached list of render states.
     ///
     /// This is always empty, but it keeps the memory around.
@@ -118,12 +165,29 @@ impl<C: GpuContext + fmt::Debug + ?Sized> fmt::Debug for Source<C> {
     }
 }
 
+impl<C: GpuContext + ?Sized> Drop for Source<C> {
+    fn drop(&mut self) {
+        // Release the resources that we own, so they are deleted while we still have the context.
+        self.atlas = None;
+        self.gradient_cache.clear();
+        self.image_atlas.clear();
+        self.mask_context.clear();
+        self.pending_readbacks.clear();
+        self.layer_images.clear();
+        self.used_layer_images.clear();
+        self.white_pixel.release();
+        self.buffers.vbos.clear();
+
+        self.deletions.drain(&mut self.context);
+    }
+}
+
 struct Buffers<C: GpuContext + ?Sized> {
     /// The rasterizer for the GPU renderer.
     rasterizer: Rasterizer,
 
-    /// The VBO for vertices.
-    vbo: VertexBuffer<C>,
+    /// The pool of VBOs for vertices.
+    vbos: VertexBufferPool<C>,
 }
 
 impl<C: GpuContext + ?Sized> Source<C> {
@@ -134,36 +198,59 @@ impl<C: GpuContext + ?Sized> Source<C> {
     {
         const WHITE: [u8; 4] = [0xFF, 0xFF, 0xFF, 0xFF];
 
+        let deletions = DeletionQueue::new();
+
+        // Every texture that we write is premultiplied RGBA.
+        let capabilities = context.capabilities(device);
+        if !capabilities
+            .formats
+            .contains(&piet::ImageFormat::RgbaPremul)
+        {
+            return Err(Pierror::NotSupported);
+        }
+        let repeat = capabilities.transparent_repeat();
+
         // Setup a white pixel texture.
         let texture = Texture::new(
             &mut context,
             device,
+            &deletions,
             InterpolationMode::NearestNeighbor,
             RepeatStrategy::Repeat,
         )
         .piet_err()?;
 
-        texture.write_texture(
-            &mut context,
-            device,
-            queue,
-            (1, 1),
-            piet::ImageFormat::RgbaSeparate,
-            Some(&WHITE),
-        );
+        texture
+            .write_texture(
+                &mut context,
+                device,
+                queue,
+                (1, 1),
+                piet::ImageFormat::RgbaPremul,
+                Some(&WHITE),
+            )
+            .piet_err()?;
 
         Ok(Self {
             white_pixel: texture,
             buffers: {
-                let vbo = VertexBuffer::new(&mut context, device).piet_err()?;
+                let vbos = VertexBufferPool::new(&mut context, device, &deletions).piet_err()?;
 
                 Buffers {
                     rasterizer: Rasterizer::new(),
-                    vbo,
+                    vbos,
                 }
             },
-            atlas: Some(Atlas::new(&mut context, device, queue)?),
-            mask_context: MaskContext::new(),
+            atlas: Some(Atlas::new(&mut context, device, queue, &deletions, repeat)?),
+            mask_context: MaskContext::new(repeat),
+            gradient_cache: GradientCache::new(),
+            image_atlas: ImageAtlas::new(repeat),
+            pending_readbacks: Vec::new(),
+            deletions,
+            layer_images: Vec::new(),
+            used_layer_images: Vec::new(),
+            capabilities,
+            size: None,
             render_states: None,
             context,
             text: Text::new(),
@@ -175,12 +262,21 @@ impl<C: GpuContext + ?Sized> Source<C> {
         &self.context
     }
 
+    /// Get the features that the backend supports.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     /// Get a mutable reference to the context.
     pub fn context_mut(&mut self) -> &mut C {
         &mut self.context
     }
 
     /// Create a new rendering context.
+    ///
+    /// If the size differs from the last one, [`resize`] is called first.
+    ///
+    /// [`resize`]: Self::resize
     pub fn render_context<'this, 'dev, 'que>(
         &'this mut self,
         device: &'dev C::Device,
@@ -188,22 +284,11 @@ impl<C: GpuContext + ?Sized> Source<C> {
         width: u32,
         height: u32,
     ) -> RenderContext<'this, 'dev, 'que, C> {
-        RenderContext {
-            state: {
-                let mut list = self.render_states.take().unwrap_or_default();
-                list.clear();
-                list.push(RenderState::default());
-                list
-            },
-            source: self,
-            device,
-            queue,
-            size: (width, height),
-            status: Ok(()),
-            tolerance: 0.1,
-            ignore_state: false,
-            bitmap_scale: 1.0,
+        if self.size != Some((width, height)) {
+            self.resize(width, height);
         }
+
+        RenderContext::new(self, device, queue, (width, height), None)
     }
 
     /// Get a reference to the text backend.
@@ -216,9 +301,97 @@ impl<C: GpuContext + ?Sized> Source<C> {
         &mut self.text
     }
 
+    /// Get the maximum number of gradient textures to keep cached.
+    pub fn gradient_cache_size(&self) -> usize {
+        self.gradient_cache.capacity()
+    }
+
+    /// Set the maximum number of gradient textures to keep cached.
+    ///
+    /// When the cache is full, the least recently used gradient is evicted. Setting this to
+    /// zero disables the cache.
+    pub fn set_gradient_cache_size(&mut self, size: usize) {
+        self.gradient_cache.set_capacity(size);
+    }
+
+    /// Remove all cached gradient textures.
+    pub fn clear_gradient_cache(&mut self) {
+        self.gradient_cache.clear();
+    }
+
+    /// Get the maximum size of images that are packed into the image atlas.
+    pub fn image_atlas_threshold(&self) -> Option<u32> {
+        self.image_atlas.threshold()
+    }
+
+    /// Set the maximum size of images that are packed into the image atlas.
+    ///
+    /// Images created with [`make_image`] whose width and height are both at or below this
+    /// size are packed into a set of shared textures, which lets icon-heavy interfaces avoid
+    /// binding a new texture for every image. If this is `None`, which is the default, every
+    /// image gets its own texture.
+    ///
+    /// Atlased images keep a copy of their pixels in memory. Drawing one with an interpolation
+    /// mode other than [`InterpolationMode::Bilinear`] uses that copy to upload the image into
+    /// a texture of its own, so that the other images in the atlas are not affected. Space in
+    /// the atlas is reused after [`gpu_flushed`] is called.
+    ///
+    /// [`make_image`]: piet::RenderContext::make_image
+    /// [`gpu_flushed`]: Source::gpu_flushed
+    pub fn set_image_atlas_threshold(&mut self, threshold: Option<u32>) {
+        self.image_atlas.set_threshold(threshold);
+    }
+
+    /// Tell the source that the render target has been resized.
+    ///
+    /// Clip masks and layers are cached at the size of the render target, so this drops the
+    /// cached ones that have a different size. Their textures are deleted the next time the GPU
+    /// is flushed. This is called by [`render_context`] whenever the size changes, so it only
+    /// needs to be called directly to release the cached textures early.
+    ///
+    /// [`render_context`]: Self::render_context
+    pub fn resize(&mut self, width: u32, height: u32) {
+        let size = (width, height);
+        self.size = Some(size);
+        self.mask_context.resize(size);
+        self.layer_images.retain(|image| image.pixel_size() == size);
+        self.used_layer_images
+            .retain(|image| image.pixel_size() == size);
+    }
+
+    /// Get the maximum number of bytes of idle clip mask textures to keep cached.
+    pub fn mask_cache_budget(&self) -> usize {
+        self.mask_context.budget()
+    }
+
+    /// Set the maximum number of bytes of idle clip mask textures to keep cached.
+    ///
+    /// Clip mask textures are kept around after the GPU has been flushed, so that later frames
+    /// can reuse them. When they take up more memory than this, the least recently used ones
+    /// are deleted. The default is 64 MiB.
+    pub fn set_mask_cache_budget(&mut self, bytes: usize) {
+        self.mask_context.set_budget(bytes);
+    }
+
     /// Indicate that we've flushed the queue and all of the GPU resources can be overwritten.
-    pub fn gpu_flushed(&mut self) {
+    ///
+    /// Until this is called, or once many buffers have been used since the last flush, every
+    /// draw uploads its vertices into a separate vertex buffer, so that it doesn't have to wait
+    /// for earlier draws. This also resolves images requested with [`read_image_deferred`], and
+    /// deletes any GPU resources that were dropped before the flush.
+    ///
+    /// [`read_image_deferred`]: RenderContext::read_image_deferred
+    pub fn gpu_flushed(&mut self, device: &C::Device, queue: &C::Queue) {
+        // The GPU is done with the images, so reading them back won't stall.
+        for readback in mem::take(&mut self.pending_readbacks) {
+            readback.resolve(&mut self.context, device, queue);
+        }
+
         self.mask_context.gpu_flushed();
+        self.image_atlas.gpu_flushed();
+        self.buffers.vbos.gpu_flushed();
+        self.layer_images.append(&mut self.used_layer_images);
+        self.deletions.drain(&mut self.context);
     }
 }
 
@@ -249,8 +422,14 @@ pub struct RenderContext<'src, 'dev, 'que, C: GpuContext + ?Sized> {
     /// Scale to apply for bitmaps.
     bitmap_scale: f64,
 
-    /// Flag to ignore the current state.
-    ignore_state: bool,
+    /// If set, ignore the current transform and clip and draw with this blend mode instead.
+    ignore_state: Option<BlendMode>,
+
+    /// The image that we are drawing into, or `None` if we are drawing on the surface.
+    target: Option<Image<C>>,
+
+    /// The layers that have been pushed and not yet popped.
+    layers: Vec<Layer<C>>,
 }
 
 #[derive(Debug)]
@@ -260,6 +439,9 @@ struct RenderState<C: GpuContext + ?Sized> {
 
     /// The current clip.
     clip: ClipState<C>,
+
+    /// The current blend mode.
+    blend_mode: BlendMode,
 }
 
 impl<C: GpuContext + ?Sized> Default for RenderState<C> {
@@ -267,10 +449,29 @@ impl<C: GpuContext + ?Sized> Default for RenderState<C> {
         Self {
             transform: Affine::IDENTITY,
             clip: ClipState::NoClip,
+            blend_mode: BlendMode::SourceOver,
         }
     }
 }
 
+/// A layer pushed by [`RenderContext::push_layer`].
+#[derive(Debug)]
+struct Layer<C: GpuContext + ?Sized> {
+    /// The opacity to composite the layer with.
+    opacity: f64,
+
+    /// Whether the layer is drawn into an offscreen image.
+    ///
+    /// Opaque layers only need their clip, so they are drawn directly onto the target below.
+    offscreen: bool,
+
+    /// The target to go back to once the layer is popped.
+    previous_target: Option<Image<C>>,
+
+    /// The length of the state stack once the layer was pushed.
+    depth: usize,
+}
+
 /// Current state for clipping.
 #[derive(Debug)]
 enum ClipState<C: GpuContext + ?Sized> {
@@ -317,6 +518,14 @@ impl<C: GpuContext + ?Sized> Clone for ClipState<C> {
 
 impl<C: GpuContext + ?Sized> Drop for RenderContext<'_, '_, '_, C> {
     fn drop(&mut self) {
+        // If we are still drawing into a layer, go back to our original target.
+        if self.layers.iter().any(|layer| layer.offscreen) {
+            let target = self.layers.swap_remove(0).previous_target;
+            if let Err(e) = self.bind_render_target(target.as_ref()) {
+                tracing::error!("failed to restore the render target: {}", e);
+            }
+        }
+
         match &mut self.state {
             TinyVec::Heap(h) => self
                 .source
@@ -335,11 +544,40 @@ impl<C: GpuContext + ?Sized> Drop for RenderContext<'_, '_, '_, C> {
 }
 
 impl<'a, 'b, 'c, C: GpuContext + ?Sized> RenderContext<'a, 'b, 'c, C> {
-    /// Temporarily ignore the transform and the clip.
+    /// Create a new render context that draws into `target`.
+    fn new(
+        source: &'a mut Source<C>,
+        device: &'b C::Device,
+        queue: &'c C::Queue,
+        size: (u32, u32),
+        target: Option<Image<C>>,
+    ) -> Self {
+        Self {
+            state: {
+                let mut list = source.render_states.take().unwrap_or_default();
+                list.clear();
+                list.push(RenderState::default());
+                list
+            },
+            source,
+            device,
+            queue,
+            size,
+            status: Ok(()),
+            tolerance: 0.1,
+            ignore_state: None,
+            bitmap_scale: 1.0,
+            target,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Temporarily ignore the transform and the clip, drawing with `blend_mode`.
     fn temporarily_ignore_state<'this>(
         &'this mut self,
+        blend_mode: BlendMode,
     ) -> TemporarilyIgnoreState<'this, 'a, 'b, 'c, C> {
-        self.ignore_state = true;
+        self.ignore_state = Some(blend_mode);
         TemporarilyIgnoreState(self)
     }
 
@@ -371,7 +609,7 @@ impl<'a, 'b, 'c, C: GpuContext + ?Sized> RenderContext<'a, 'b, 'c, C> {
             })?;
 
         // Push the incoming buffers.
-        self.push_buffers(brush.texture(self.size).as_ref().map(|t| t.texture()))
+        self.push_brush_buffers(brush)
     }
 
     fn stroke_impl(
@@ -397,78 +635,134 @@ impl<'a, 'b, 'c, C: GpuContext + ?Sized> RenderContext<'a, 'b, 'c, C> {
         )?;
 
         // Push the incoming buffers.
-        self.push_buffers(brush.texture(self.size).as_ref().map(|t| t.texture()))
+        self.push_brush_buffers(brush)
+    }
+
+    /// Push the values currently in the renderer to the GPU, filled with a brush.
+    fn push_brush_buffers(&mut self, brush: &Brush<C>) -> Result<(), Pierror> {
+        let texture = brush.texture(self.size).map(|image| image.texture());
+        self.push_buffers_with_material(texture, brush.as_material())
     }
 
     /// Push the values currently in the renderer to the GPU.
     fn push_buffers(&mut self, texture: Option<&Texture<C>>) -> Result<(), Pierror> {
-        // Upload the vertex and index buffers.
-        self.source.buffers.vbo.upload(
-            &mut self.source.context,
-            self.device,
-            self.queue,
-            self.source.buffers.rasterizer.vertices(),
-            self.source.buffers.rasterizer.indices(),
-        );
+        self.push_buffers_with_material(texture, None)
+    }
 
-        // Decide which mask and transform to use.
-        let (transform, mask_texture, clip_rect, used_mask) = if self.ignore_state {
-            (
-                Affine::scale(self.bitmap_scale),
-                &self.source.white_pixel,
-                None,
-                false,
-            )
-        } else {
-            let state = self.state.last_mut().unwrap();
+    /// Push the values currently in the renderer to the GPU, shaded with an optional material.
+    fn push_buffers_with_material(
+        &mut self,
+        texture: Option<&Texture<C>>,
+        material: Option<&Material>,
+    ) -> Result<(), Pierror> {
+        let result = self.draw_rasterized(texture, material);
+
+        // Clear the original buffers, even if drawing failed, so the next draw doesn't repeat it.
+        self.source.buffers.rasterizer.clear();
+
+        result
+    }
 
-            let (has_mask, clip_rect, mask) = match &mut state.clip {
-                ClipState::NoClip => (false, None, &self.source.white_pixel),
-                ClipState::SimpleRect(rect) => (false, Some(*rect), &self.source.white_pixel),
-                ClipState::Mask(mask) => (
-                    true,
+    /// Draw the contents of the rasterizer.
+    fn draw_rasterized(
+        &mut self,
+        texture: Option<&Texture<C>>,
+        material: Option<&Material>,
+    ) -> Result<(), Pierror> {
+        // Decide which mask and transform to use.
+        let (transform, mask_texture, clip_rect, used_mask, blend_mode) =
+            if let Some(blend_mode) = self.ignore_state {
+                (
+                    Affine::scale(self.bitmap_scale),
+                    &self.source.white_pixel,
                     None,
-                    self.source.mask_context.texture(
-                        mask,
-                        &mut self.source.context,
-                        self.device,
-                        self.queue,
+                    false,
+                    blend_mode,
+                )
+            } else {
+                let state = self.state.last_mut().ok_or(Pierror::StackUnbalance)?;
+
+                let (has_mask, clip_rect, mask) = match &mut state.clip {
+                    ClipState::NoClip => (false, None, &self.source.white_pixel),
+                    ClipState::SimpleRect(rect) => (false, Some(*rect), &self.source.white_pixel),
+                    ClipState::Mask(mask) => (
+                        true,
+                        None,
+                        self.source.mask_context.texture(
+                            mask,
+                            &mut self.source.context,
+                            self.device,
+                            self.queue,
+                            &self.source.deletions,
+                        )?,
                     ),
-                ),
+                };
+
+                (
+                    Affine::scale(self.bitmap_scale) * state.transform,
+                    mask,
+                    clip_rect,
+                    has_mask,
+                    state.blend_mode,
+                )
             };
 
-            (
-                Affine::scale(self.bitmap_scale) * state.transform,
-                mask,
-                clip_rect,
-                has_mask,
-            )
-        };
-
         // Decide the texture to use.
         let texture = texture.unwrap_or(&self.source.white_pixel);
+        let blend_mode = self.source.capabilities.blend_mode(blend_mode);
 
-        // Draw!
-        self.source
-            .context
-            .push_buffers(gpu_types::BufferPush {
-                device: self.device,
-                queue: self.queue,
-                vertex_buffer: self.source.buffers.vbo.resource(),
-                current_texture: texture.resource(),
-                mask_texture: mask_texture.resource(),
-                transform: &transform,
-                viewport_size: self.size,
-                clip: clip_rect,
-            })
-            .piet_err()?;
+        // Split the mesh up if the backend can't draw all of it at once.
+        let chunks = rasterizer::split_mesh(
+            self.source.buffers.rasterizer.vertices(),
+            self.source.buffers.rasterizer.indices(),
+            self.source.capabilities.max_vertices,
+        );
 
-        // Clear the original buffers.
-        self.source.buffers.rasterizer.clear();
+        for (vertices, indices) in &chunks {
+            // Use a fresh vertex buffer, so we don't wait on the GPU to finish the last draw.
+            let vbo = self
+                .source
+                .buffers
+                .vbos
+                .acquire(
+                    &mut self.source.context,
+                    self.device,
+                    &self.source.deletions,
+                )
+                .piet_err()?;
+
+            // Upload the vertex and index buffers.
+            let result = vbo
+                .upload(
+                    &mut self.source.context,
+                    self.device,
+                    self.queue,
+                    vertices,
+                    indices,
+                )
+                .and_then(|()| {
+                    // Draw!
+                    self.source.context.push_buffers(gpu_types::BufferPush {
+                        device: self.device,
+                        queue: self.queue,
+                        vertex_buffer: vbo.resource(),
+                        current_texture: texture.resource(),
+                        mask_texture: mask_texture.resource(),
+                        transform: &transform,
+                        viewport_size: self.size,
+                        clip: clip_rect,
+                        blend_mode,
+                        material,
+                    })
+                });
+
+            self.source.buffers.vbos.retire(vbo);
+            result.piet_err()?;
+        }
 
         // Mark the mask as used so we don't overwrite it.
         if used_mask {
-            if let Some(mask) = &mut self.state.last_mut().unwrap().clip.as_mut() {
+            if let Some(mask) = self.state.last_mut().and_then(|state| state.clip.as_mut()) {
                 self.source.mask_context.mark_used(mask);
             }
         }
@@ -477,36 +771,772 @@ impl<'a, 'b, 'c, C: GpuContext + ?Sized> RenderContext<'a, 'b, 'c, C> {
     }
 
     fn clip_impl(&mut self, shape: impl Shape) {
-        let state = self.state.last_mut().unwrap();
+        let state = match self.state.last_mut() {
+            Some(state) => state,
+            None => {
+                self.status = Err(Pierror::StackUnbalance);
+                return;
+            }
+        };
 
         // If this shape is just a rectangle, use a simple scissor rect instead.
-        if let Some(rect) = shape.as_rect() {
+        if let Some(rect) = shape.as_rect().filter(|_| self.source.capabilities.scissor) {
             if let ClipState::NoClip = &state.clip {
                 state.clip = ClipState::SimpleRect(rect);
                 return;
             }
         }
 
-        let mask = match &mut state.clip {
-            ClipState::Mask(mask) => mask,
-            ClipState::SimpleRect(rect) => {
+        if !matches!(state.clip, ClipState::Mask(_)) {
+            let mut mask = match Mask::new(self.size.0, self.size.1) {
+                Ok(mask) => mask,
+                Err(_) => {
+                    // The target has no pixels, so there is nothing to clip to.
+                    state.clip = ClipState::SimpleRect(Rect::ZERO);
+                    return;
+                }
+            };
+
+            if let ClipState::SimpleRect(rect) = state.clip {
                 // Create a clip mask with the existing rectangle
-                let mut mask = Mask::new(self.size.0, self.size.1);
                 self.source
                     .mask_context
-                    .add_path(&mut mask, *rect, self.tolerance);
-                state.clip = ClipState::Mask(mask);
-                state.clip.as_mut().unwrap()
+                    .add_path(&mut mask, rect, self.tolerance);
             }
-            clip @ ClipState::NoClip => {
-                *clip = ClipState::Mask(Mask::new(self.size.0, self.size.1));
-                clip.as_mut().unwrap()
+            state.clip = ClipState::Mask(mask);
+        }
+
+        if let ClipState::Mask(mask) = &mut state.clip {
+            self.source
+                .mask_context
+                .add_path(mask, shape, self.tolerance);
+        }
+    }
+
+    /// Create a gradient brush with the given options.
+    ///
+    /// [`piet::RenderContext::gradient`] is equivalent to calling this with the default options.
+    pub fn gradient_with_options(
+        &mut self,
+        gradient: impl Into<FixedGradient>,
+        options: GradientOptions,
+    ) -> Result<Brush<C>, Pierror> {
+        let gradient = gradient.into();
+        let (device, queue) = (self.device, self.queue);
+        let Source {
+            context,
+            gradient_cache,
+            deletions,
+            ..
+        } = &mut *self.source;
+
+        gradient_cache.get_or_insert_with(&gradient, options, || match gradient.clone() {
+            FixedGradient::Linear(linear) => {
+                Brush::linear_gradient(context, device, queue, deletions, linear, options)
+            }
+            FixedGradient::Radial(radial) => {
+                Brush::radial_gradient(context, device, queue, deletions, radial, options)
+            }
+        })
+    }
+
+    /// Draw an arbitrary triangle mesh.
+    ///
+    /// Every three entries in `indices` form a triangle out of the corresponding `vertices`.
+    /// The vertices are drawn under the current transform and clip. The color of each vertex
+    /// is multiplied by the color sampled from `image` at the vertex's UV coordinates, which
+    /// range from `0.0` to `1.0` across the image after being divided by the vertex's `q`. If no
+    /// image is provided, only the vertex colors are used.
+    ///
+    /// This is useful for drawing things like mesh gradients and Gouraud-shaded geometry. If
+    /// the mesh is invalid, an error is reported through [`status`].
+    ///
+    /// [`status`]: piet::RenderContext::status
+    pub fn draw_mesh(&mut self, vertices: &[Vertex], indices: &[u32], image: Option<&Image<C>>) {
+        let in_bounds = |&index: &u32| (index as usize) < vertices.len();
+        if indices.len() % 3 != 0 || !indices.iter().all(in_bounds) {
+            self.status = Err(Pierror::InvalidInput);
+            return;
+        }
+        if indices.is_empty() {
+            return;
+        }
+
+        // Map the UV coordinates into the image's area of the texture. They are multiplied by
+        // `q`, so the offset of that area has to be as well.
+        let vertices = vertices.iter().map(|vertex| match image {
+            Some(image) => {
+                let origin = image.map_uv(Point::ZERO).to_vec2();
+                let uv = image.map_uv(Point::new(vertex.uv[0] as f64, vertex.uv[1] as f64))
+                    + origin * (vertex.q as f64 - 1.0);
+                Vertex {
+                    uv: [uv.x as f32, uv.y as f32],
+                    ..*vertex
+                }
+            }
+            None => *vertex,
+        });
+
+        self.source.buffers.rasterizer.fill_mesh(vertices, indices);
+        if let Err(e) = self.push_buffers(image.map(|image| image.texture())) {
+            self.status = Err(e);
+        }
+    }
+
+    /// Draw an area of an image mapped onto an arbitrary quadrilateral.
+    ///
+    /// `corners` are the points that the top-left, top-right, bottom-right and bottom-left
+    /// corners of `src_rect` are drawn at, under the current transform and clip. `mapping`
+    /// decides how the image is stretched between those corners.
+    ///
+    /// If the quadrilateral cannot be mapped, an error is reported through [`status`].
+    ///
+    /// [`status`]: piet::RenderContext::status
+    pub fn draw_image_quad(
+        &mut self,
+        image: &Image<C>,
+        src_rect: impl Into<Rect>,
+        corners: [Point; 4],
+        mapping: QuadMapping,
+        interp: InterpolationMode,
+    ) {
+        // Zero-sized images have nothing to draw and no UV space to map into.
+        let size = image.size();
+        if size.width <= 0.0 || size.height <= 0.0 {
+            return;
+        }
+
+        let src_rect = src_rect.into();
+        let uv_rect = Rect::new(
+            src_rect.x0 / size.width,
+            src_rect.y0 / size.height,
+            src_rect.x1 / size.width,
+            src_rect.y1 / size.height,
+        );
+
+        let (vertices, indices) =
+            match quad::quad_mesh(corners, uv_rect, mapping, piet::Color::WHITE) {
+                Some(mesh) => mesh,
+                None => {
+                    self.status = Err(Pierror::InvalidInput);
+                    return;
+                }
+            };
+
+        let image = match self.sampled_image(image, interp) {
+            Ok(image) => image,
+            Err(e) => {
+                self.status = Err(e);
+                return;
             }
         };
+        self.draw_mesh(&vertices, &indices, Some(&image));
+    }
 
-        self.source
-            .mask_context
-            .add_path(mask, shape, self.tolerance);
+    /// Draw an area of an image into a rectangle that is then transformed by `transform`.
+    ///
+    /// This is equivalent to drawing the image with [`draw_image_area`] after applying
+    /// `transform`, but without changing the current transform. This makes it easy to draw
+    /// rotated or skewed images.
+    ///
+    /// [`draw_image_area`]: piet::RenderContext::draw_image_area
+    pub fn draw_image_area_transformed(
+        &mut self,
+        image: &Image<C>,
+        src_rect: impl Into<Rect>,
+        dst_rect: impl Into<Rect>,
+        transform: Affine,
+        interp: InterpolationMode,
+    ) {
+        let dst_rect = dst_rect.into();
+        let corners = [
+            Point::new(dst_rect.x0, dst_rect.y0),
+            Point::new(dst_rect.x1, dst_rect.y0),
+            Point::new(dst_rect.x1, dst_rect.y1),
+            Point::new(dst_rect.x0, dst_rect.y1),
+        ]
+        .map(|corner| transform * corner);
+
+        self.draw_image_quad(image, src_rect, corners, QuadMapping::Bilinear, interp);
+    }
+
+    /// Draw an image as a nine-slice image, also known as a nine-patch image.
+    ///
+    /// `insets` divides the image into four corners, four edges and a center, in the image's
+    /// coordinate space. The corners are drawn into the corners of `dst_rect` at their original
+    /// size, scaled down only if they would not fit. The edges and the center are stretched or
+    /// tiled to fill the rest of the rectangle, depending on `mode`.
+    ///
+    /// If the insets do not fit in the image, an error is reported through [`status`].
+    ///
+    /// [`status`]: piet::RenderContext::status
+    pub fn draw_image_nine_slice(
+        &mut self,
+        image: &Image<C>,
+        insets: impl Into<Insets>,
+        dst_rect: impl Into<Rect>,
+        mode: NineSliceMode,
+        interp: InterpolationMode,
+    ) {
+        let size = image.size();
+        let slices = match nine_slice::nine_slices(size, insets.into(), dst_rect.into(), mode) {
+            Some(slices) => slices,
+            None => {
+                self.status = Err(Pierror::InvalidInput);
+                return;
+            }
+        };
+
+        let image = match self.sampled_image(image, interp) {
+            Ok(image) => image,
+            Err(e) => {
+                self.status = Err(e);
+                return;
+            }
+        };
+        let rects = slices.into_iter().map(|(pos, src)| TessRect {
+            pos,
+            uv: image.map_uv_rect(Rect::new(
+                src.x0 / size.width,
+                src.y0 / size.height,
+                src.x1 / size.width,
+                src.y1 / size.height,
+            )),
+            color: piet::Color::WHITE,
+        });
+
+        if let Err(e) = self.fill_rects(rects, Some(image.texture())) {
+            self.status = Err(e);
+        }
+    }
+
+    /// Generate mipmaps for an image.
+    ///
+    /// Once an image has mipmaps, drawing it with [`InterpolationMode::Bilinear`] samples it
+    /// trilinearly, which avoids aliasing when the image is drawn much smaller than its actual
+    /// size. Mipmaps need to be generated again after the image's contents change.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the backend does not support mipmapping, or if the
+    /// image has been packed into the image atlas.
+    pub fn generate_mipmaps(&mut self, image: &Image<C>) -> Result<(), Pierror> {
+        if image.is_atlased() {
+            return Err(Pierror::NotSupported);
+        }
+
+        let generated = image
+            .texture()
+            .generate_mipmaps(&mut self.source.context, self.device, self.queue)
+            .piet_err()?;
+
+        if generated {
+            Ok(())
+        } else {
+            Err(Pierror::NotSupported)
+        }
+    }
+
+    /// Decode an image file and upload it into an image.
+    ///
+    /// PNG, JPEG, WebP and GIF images are supported. The image is rotated and flipped according
+    /// to its EXIF orientation, and only the first frame of an animated image is used.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the image is in some other format.
+    #[cfg(feature = "image-decoding")]
+    pub fn load_image(&mut self, data: &[u8]) -> Result<Image<C>, Pierror> {
+        let decoded = decode::decode_image(data)?;
+        piet::RenderContext::make_image(
+            self,
+            decoded.width,
+            decoded.height,
+            &decoded.data,
+            piet::ImageFormat::RgbaSeparate,
+        )
+    }
+
+    /// Read the pixels of an image back into CPU memory.
+    ///
+    /// The pixels are returned with premultiplied alpha, as [`ImageFormat::RgbaPremul`]. This
+    /// may stall until the GPU has finished drawing; use [`read_image_deferred`] to avoid that.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the backend does not support reading textures.
+    ///
+    /// [`ImageFormat::RgbaPremul`]: piet::ImageFormat::RgbaPremul
+    /// [`read_image_deferred`]: Self::read_image_deferred
+    pub fn read_image(&mut self, image: &Image<C>) -> Result<piet::ImageBuf, Pierror> {
+        readback::read_image(&mut self.source.context, self.device, self.queue, image)
+    }
+
+    /// Read the pixels of an image back into CPU memory once the GPU is done with it.
+    ///
+    /// The returned handle is resolved the next time [`Source::gpu_flushed`] is called, once the
+    /// GPU has finished the drawing commands issued so far. This is useful for taking
+    /// screenshots with [`capture_image_area`] without stalling the CPU on the GPU.
+    ///
+    /// [`capture_image_area`]: piet::RenderContext::capture_image_area
+    pub fn read_image_deferred(&mut self, image: &Image<C>) -> ImageReadback {
+        let (pending, handle) = PendingReadback::new(image);
+        self.source.pending_readbacks.push(pending);
+        handle
+    }
+
+    /// Replace the contents of an image with new pixels.
+    ///
+    /// If the new pixels have the same size and format as the image, they are written into the
+    /// image's existing texture, so every clone of the image sees the new contents. Otherwise, a
+    /// new texture is allocated and only `image` is updated to refer to it.
+    ///
+    /// Mipmaps need to be generated again after the image's contents change.
+    pub fn update_image(
+        &mut self,
+        image: &mut Image<C>,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: piet::ImageFormat,
+    ) -> Result<(), Pierror> {
+        let (data, size, converted_format) =
+            image::normalize_image_data((width, height), buf, format)?;
+        if size != image.pixel_size() || format != image.format() {
+            *image = piet::RenderContext::make_image(self, width, height, buf, format)?;
+            return Ok(());
+        }
+
+        self.write_image_area(image, (0, 0), size, &data, converted_format)
+    }
+
+    /// Write new pixels into a rectangular area of an image.
+    ///
+    /// `offset` and `size` are in pixels and must lie within the image, and `format` must be the
+    /// format that the image was created with. This always writes into the image's existing
+    /// texture, so every clone of the image sees the new contents.
+    pub fn update_image_area(
+        &mut self,
+        image: &Image<C>,
+        offset: (usize, usize),
+        size: (usize, usize),
+        buf: &[u8],
+        format: piet::ImageFormat,
+    ) -> Result<(), Pierror> {
+        if format != image.format() {
+            return Err(Pierror::InvalidInput);
+        }
+        let (data, size, format) = image::normalize_image_data(size, buf, format)?;
+
+        let (image_width, image_height) = image.pixel_size();
+        let fits = |offset: usize, size: u32, image_size: u32| {
+            offset
+                .checked_add(size as usize)
+                .map_or(false, |end| end <= image_size as usize)
+        };
+        if !fits(offset.0, size.0, image_width) || !fits(offset.1, size.1, image_height) {
+            return Err(Pierror::InvalidInput);
+        }
+
+        self.write_image_area(
+            image,
+            (offset.0 as u32, offset.1 as u32),
+            size,
+            &data,
+            format,
+        )
+    }
+
+    /// Write already-validated pixels into an area of an image's texture.
+    fn write_image_area(
+        &mut self,
+        image: &Image<C>,
+        offset: (u32, u32),
+        size: (u32, u32),
+        data: &[u8],
+        format: piet::ImageFormat,
+    ) -> Result<(), Pierror> {
+        if size.0 == 0 || size.1 == 0 {
+            return Ok(());
+        }
+
+        let (base_x, base_y) = image.offset();
+        image
+            .texture()
+            .write_subtexture(
+                &mut self.source.context,
+                self.device,
+                self.queue,
+                (base_x + offset.0, base_y + offset.1),
+                size,
+                format,
+                data,
+            )
+            .piet_err()?;
+
+        // Keep the atlas's copy of the image up to date.
+        if let Some(slot) = image.atlas_slot() {
+            slot.write_area(offset, size, data);
+        }
+
+        Ok(())
+    }
+
+    /// Draw into a new image instead of this context's render target.
+    ///
+    /// `size` is the size of the image before the bitmap scale is applied. The closure receives
+    /// a render context that draws into the image, which starts out transparent, with no
+    /// transform or clip and the same bitmap scale and tolerance as this context. Once the
+    /// closure returns, drawing goes back to this context's render target. Contexts can be
+    /// nested, so the closure may call `render_to_image` again.
+    ///
+    /// This is useful for caching the drawing of expensive content, or for drawing effects that
+    /// need an intermediate image.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the backend does not support render targets.
+    pub fn render_to_image<F>(&mut self, size: impl Into<Size>, f: F) -> Result<Image<C>, Pierror>
+    where
+        F: FnOnce(&mut RenderContext<'_, '_, '_, C>) -> Result<(), Pierror>,
+    {
+        let size = size.into();
+        let width = (size.width * self.bitmap_scale).ceil();
+        let height = (size.height * self.bitmap_scale).ceil();
+
+        // This also catches NaN sizes.
+        let (max_width, max_height) = self.source.context.max_texture_size(self.device);
+        if !(width >= 1.0
+            && height >= 1.0
+            && width <= max_width as f64
+            && height <= max_height as f64)
+        {
+            return Err(Pierror::InvalidInput);
+        }
+        let pixel_size = (width as u32, height as u32);
+
+        let image = self.create_target_image(size, pixel_size)?;
+        self.bind_render_target(Some(&image))?;
+
+        let result = {
+            let mut context = RenderContext::new(
+                &mut *self.source,
+                self.device,
+                self.queue,
+                pixel_size,
+                Some(image.clone()),
+            );
+            context.tolerance = self.tolerance;
+            context.bitmap_scale = self.bitmap_scale;
+
+            f(&mut context).and_then(|()| mem::replace(&mut context.status, Ok(())))
+        };
+
+        // Go back to drawing into our own target, even if drawing the image failed.
+        let target = self.target.clone();
+        let rebound = self.bind_render_target(target.as_ref());
+
+        result?;
+        rebound?;
+        Ok(image)
+    }
+
+    /// Create a brush that fills shapes with a backend-specific material.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the material's identifier is not listed in
+    /// [`Capabilities::materials`]. See [`Material`] for more information.
+    pub fn material_brush(&self, material: Material) -> Result<Brush<C>, Pierror> {
+        if !self.source.capabilities.materials.contains(&material.id()) {
+            return Err(Pierror::NotSupported);
+        }
+
+        Ok(Brush::material(material))
+    }
+
+    /// Get the blend mode that drawing operations currently use.
+    pub fn blend_mode(&self) -> BlendMode {
+        self.state
+            .last()
+            .map_or(BlendMode::SourceOver, |state| state.blend_mode)
+    }
+
+    /// Set the blend mode for subsequent drawing operations.
+    ///
+    /// The blend mode is part of the render state, so it is saved and restored along with the
+    /// transform and the clip. Layers are composited with the blend mode that is current when
+    /// they are popped, while [`clear`] always uses [`BlendMode::SourceOver`]. Backends that lack
+    /// advanced blending approximate the separable blend modes; see [`BlendMode`] for details.
+    ///
+    /// Returns [`Pierror::NotSupported`] and keeps the current blend mode for modes that would
+    /// change the destination where shapes don't cover it, such as [`BlendMode::Source`]. See
+    /// [`BlendMode`] for the full list.
+    ///
+    /// [`clear`]: piet::RenderContext::clear
+    pub fn set_blend_mode(&mut self, blend_mode: BlendMode) -> Result<(), Pierror> {
+        if !blend_mode.preserves_uncovered() {
+            return Err(Pierror::NotSupported);
+        }
+
+        let state = self.state.last_mut().ok_or(Pierror::StackUnbalance)?;
+        state.blend_mode = blend_mode;
+        Ok(())
+    }
+
+    /// Push a layer that is composited onto the target with the given opacity once it is
+    /// popped.
+    ///
+    /// Everything drawn until the matching [`pop_layer`] is drawn into an offscreen image, which
+    /// is then blended as a whole, so overlapping shapes inside of the layer fade as a unit.
+    /// The layer also saves the render state, like [`save`], so transforms and clips set inside
+    /// of it are undone when it is popped. `opacity` is clamped between zero and one.
+    ///
+    /// The layer is composited with the current blend mode, while drawing inside of it starts
+    /// out with [`BlendMode::SourceOver`]. Opaque layers are drawn directly onto the target if
+    /// the current blend mode is [`BlendMode::SourceOver`], since compositing them would not
+    /// change anything.
+    ///
+    /// Returns [`Pierror::NotSupported`] if the layer needs an offscreen image and the backend
+    /// does not support render targets.
+    ///
+    /// [`pop_layer`]: Self::pop_layer
+    /// [`save`]: piet::RenderContext::save
+    pub fn push_layer(&mut self, opacity: f64) -> Result<(), Pierror> {
+        self.push_layer_impl(opacity, None::<Rect>)
+    }
+
+    /// Push a layer like [`push_layer`], and clip everything drawn inside of it to `clip`.
+    ///
+    /// [`push_layer`]: Self::push_layer
+    pub fn push_layer_with_clip(&mut self, opacity: f64, clip: impl Shape) -> Result<(), Pierror> {
+        self.push_layer_impl(opacity, Some(clip))
+    }
+
+    /// Pop the layer pushed by the last call to [`push_layer`] and composite it onto the
+    /// target below it.
+    ///
+    /// Any render states saved inside of the layer are restored as well, and the layer is
+    /// composited with the blend mode of the restored state. Returns
+    /// [`Pierror::StackUnbalance`] if there is no layer to pop.
+    ///
+    /// [`push_layer`]: Self::push_layer
+    pub fn pop_layer(&mut self) -> Result<(), Pierror> {
+        let layer = self.layers.pop().ok_or(Pierror::StackUnbalance)?;
+
+        // Restore the render states pushed since the layer, including its own.
+        while self.state.len() >= layer.depth {
+            self.pop_state();
+        }
+
+        if !layer.offscreen {
+            return Ok(());
+        }
+
+        let image =
+            mem::replace(&mut self.target, layer.previous_target).ok_or(Pierror::StackUnbalance)?;
+        let target = self.target.clone();
+        self.bind_render_target(target.as_ref())?;
+
+        // The layer's contents have already been clipped, so draw it over the whole viewport.
+        let viewport = Rect::new(
+            0.0,
+            0.0,
+            self.size.0 as f64 / self.bitmap_scale,
+            self.size.1 as f64 / self.bitmap_scale,
+        );
+        let blend_mode = self.blend_mode();
+        let result = {
+            let ignore_state = self.temporarily_ignore_state(blend_mode);
+            ignore_state.0.fill_rects(
+                [TessRect {
+                    pos: viewport,
+                    uv: Rect::new(0.0, 0.0, 1.0, 1.0),
+                    color: piet::Color::WHITE.with_alpha(layer.opacity),
+                }],
+                Some(image.texture()),
+            )
+        };
+
+        // The composite draw still needs the image, so don't reuse it until the GPU is flushed.
+        self.source.used_layer_images.push(image);
+        result
+    }
+
+    fn push_layer_impl(&mut self, opacity: f64, clip: Option<impl Shape>) -> Result<(), Pierror> {
+        if opacity.is_nan() {
+            return Err(Pierror::InvalidInput);
+        }
+        let opacity = opacity.clamp(0.0, 1.0);
+        let offscreen = (opacity < 1.0 || self.blend_mode() != BlendMode::SourceOver)
+            && self.size.0 > 0
+            && self.size.1 > 0;
+        let previous_target = self.target.clone();
+
+        if offscreen {
+            // Reuse an image from an earlier layer if we can.
+            let size = self.size;
+            self.source
+                .layer_images
+                .retain(|image| image.pixel_size() == size);
+            let image = match self.source.layer_images.pop() {
+                Some(image) => image,
+                None => self.create_target_image(Size::new(size.0 as f64, size.1 as f64), size)?,
+            };
+
+            self.bind_render_target(Some(&image))?;
+            self.source
+                .context
+                .clear(self.device, self.queue, piet::Color::TRANSPARENT);
+            self.target = Some(image);
+        }
+
+        // The blend mode is applied once when compositing the layer, not to its contents.
+        piet::RenderContext::save(self)?;
+        if let Some(state) = self.state.last_mut() {
+            state.blend_mode = BlendMode::SourceOver;
+        }
+        if let Some(clip) = clip {
+            self.clip_impl(clip);
+        }
+
+        self.layers.push(Layer {
+            opacity,
+            offscreen,
+            previous_target,
+            depth: self.state.len(),
+        });
+
+        Ok(())
+    }
+
+    /// Create a transparent image that can be used as a render target.
+    fn create_target_image(
+        &mut self,
+        size: Size,
+        pixel_size: (u32, u32),
+    ) -> Result<Image<C>, Pierror> {
+        let texture = Texture::new(
+            &mut self.source.context,
+            self.device,
+            &self.source.deletions,
+            InterpolationMode::Bilinear,
+            self.source.capabilities.transparent_repeat(),
+        )
+        .piet_err()?;
+        texture
+            .write_texture(
+                &mut self.source.context,
+                self.device,
+                self.queue,
+                pixel_size,
+                piet::ImageFormat::RgbaPremul,
+                None,
+            )
+            .piet_err()?;
+
+        Ok(Image::new(
+            texture,
+            size,
+            pixel_size,
+            piet::ImageFormat::RgbaPremul,
+        ))
+    }
+
+    /// Upload normalized pixels into an image with a texture of its own.
+    fn make_texture_image(
+        &mut self,
+        size: (u32, u32),
+        buf: &[u8],
+        format: piet::ImageFormat,
+    ) -> Result<Image<C>, Pierror> {
+        let tex = Texture::new(
+            &mut self.source.context,
+            self.device,
+            &self.source.deletions,
+            InterpolationMode::Bilinear,
+            self.source.capabilities.transparent_repeat(),
+        )
+        .piet_err()?;
+
+        // Without border colors, surround the image with transparent pixels instead.
+        let padding = if self.source.capabilities.border_color {
+            0
+        } else {
+            1
+        };
+        let (buf, texture_size) = if padding > 0 {
+            (
+                Cow::Owned(image::pad_image(buf, size, padding)),
+                (size.0 + padding * 2, size.1 + padding * 2),
+            )
+        } else {
+            (Cow::Borrowed(buf), size)
+        };
+
+        tex.write_texture(
+            &mut self.source.context,
+            self.device,
+            self.queue,
+            texture_size,
+            format,
+            Some(&buf),
+        )
+        .piet_err()?;
+
+        Ok(Image::padded(
+            tex,
+            Size::new(size.0 as f64, size.1 as f64),
+            size,
+            format,
+            padding,
+        ))
+    }
+
+    /// Get a version of `image` whose texture is sampled with `interp`.
+    ///
+    /// Atlased images share their texture with other images, so the interpolation mode of an
+    /// atlas page is never changed. Instead, drawing an atlased image without bilinear filtering
+    /// draws a copy of it that has a texture of its own.
+    fn sampled_image<'img>(
+        &mut self,
+        image: &'img Image<C>,
+        interp: InterpolationMode,
+    ) -> Result<Cow<'img, Image<C>>, Pierror> {
+        let image = match image.atlas_slot() {
+            Some(_) if interp == InterpolationMode::Bilinear => return Ok(Cow::Borrowed(image)),
+            Some(slot) => Cow::Owned(slot.detached(|slot| {
+                let format = piet::ImageFormat::RgbaPremul;
+                self.make_texture_image(image.pixel_size(), &slot.data(), format)
+            })?),
+            None => Cow::Borrowed(image),
+        };
+
+        image
+            .texture()
+            .set_interpolation(&mut self.source.context, self.device, interp)
+            .piet_err()?;
+        Ok(image)
+    }
+
+    /// Pop the top render state, reclaiming its clip mask.
+    fn pop_state(&mut self) {
+        if let Some(mut state) = self.state.pop() {
+            self.source.mask_context.reclaim(
+                mem::replace(&mut state.clip, ClipState::NoClip)
+                    .into_mask()
+                    .into_iter(),
+            );
+        }
+    }
+
+    /// Tell the backend to draw into `target`, or into the surface if it is `None`.
+    fn bind_render_target(&mut self, target: Option<&Image<C>>) -> Result<(), Pierror> {
+        let bound = self
+            .source
+            .context
+            .bind_render_target(
+                self.device,
+                self.queue,
+                target.map(|image| image.texture().resource()),
+            )
+            .piet_err()?;
+
+        if bound {
+            Ok(())
+        } else {
+            Err(Pierror::NotSupported)
+        }
     }
 
     /// Get the source of this render context.
@@ -557,7 +1587,7 @@ macro_rules! leap {
     ($self:expr, $e:expr, $err:expr) => {{
         match $e {
             Ok(v) => v,
-            Err(e) => {
+            Err(_) => {
                 let err = $err;
                 $self.status = Err(err.into());
                 return;
@@ -581,14 +1611,7 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
     }
 
     fn gradient(&mut self, gradient: impl Into<FixedGradient>) -> Result<Self::Brush, Pierror> {
-        match gradient.into() {
-            FixedGradient::Linear(linear) => {
-                Brush::linear_gradient(&mut self.source.context, self.device, self.queue, linear)
-            }
-            FixedGradient::Radial(radial) => {
-                Brush::radial_gradient(&mut self.source.context, self.device, self.queue, radial)
-            }
-        }
+        self.gradient_with_options(gradient, GradientOptions::default())
     }
 
     fn clear(&mut self, region: impl Into<Option<Rect>>, mut color: piet::Color) {
@@ -617,7 +1640,7 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         }
 
         // Ignore clipping mask and transform.
-        let ignore_state = self.temporarily_ignore_state();
+        let ignore_state = self.temporarily_ignore_state(BlendMode::SourceOver);
 
         // Otherwise, fall back to filling in the screen rectangle.
         let result = ignore_state.0.fill_rects(
@@ -699,23 +1722,36 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
 
         impl<G: GpuContext + ?Sized> Drop for RestoreAtlas<'_, '_, '_, '_, G> {
             fn drop(&mut self) {
-                self.context.source.atlas = Some(self.atlas.take().unwrap());
+                self.context.source.atlas = self.atlas.take();
             }
         }
 
         let pos = pos.into();
+        let atlas = leap!(
+            self,
+            self.source.atlas.take().ok_or(()),
+            Pierror::BackendError("The glyph atlas is not available.".into())
+        );
         let mut restore = RestoreAtlas {
-            atlas: self.source.atlas.take(),
+            atlas: Some(atlas),
             context: self,
         };
 
         // Iterate over the glyphs and use them to write.
-        let texture = restore.atlas.as_ref().unwrap().texture().clone();
+        let texture = match &restore.atlas {
+            Some(atlas) => atlas.texture().clone(),
+            None => return,
+        };
+        let atlas = match restore.atlas.as_mut() {
+            Some(atlas) => atlas,
+            None => return,
+        };
 
         let text = restore.context.text().clone();
         let device = restore.context.device;
         let queue = restore.context.queue;
         let mut line_state = LineProcessor::new();
+        let mut uv_result = Ok(());
         let rects = layout
             .buffer()
             .layout_runs()
@@ -726,7 +1762,6 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
                     .map(move |glyph| (glyph, run.line_y as f64))
             })
             .filter_map({
-                let atlas = restore.atlas.as_mut().unwrap();
                 |(glyph, line_y)| {
                     // Get the rectangle in texture space representing the glyph.
                     let GlyphData {
@@ -744,7 +1779,11 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
                     }) {
                         Some(Ok(rect)) => rect,
                         Some(Err(e)) => {
+                            // Keep drawing the other glyphs, but report the first error.
                             tracing::trace!("failed to get uv rect: {}", e);
+                            if uv_result.is_ok() {
+                                uv_result = Err(e);
+                            }
                             return None;
                         }
                         None => {
@@ -816,15 +1855,17 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
             }
         };
 
+        leap!(self, uv_result);
         leap!(self, result);
         leap!(self, lines_result);
     }
 
     fn save(&mut self) -> Result<(), Pierror> {
-        let last = self.state.last().unwrap();
+        let last = self.state.last().ok_or(Pierror::StackUnbalance)?;
         self.state.push(RenderState {
             transform: last.transform,
             clip: last.clip.clone(),
+            blend_mode: last.blend_mode,
         });
         Ok(())
     }
@@ -834,26 +1875,39 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
             return Err(Pierror::StackUnbalance);
         }
 
-        let mut state = self.state.pop().unwrap();
-        self.source.mask_context.reclaim(
-            mem::replace(&mut state.clip, ClipState::NoClip)
-                .into_mask()
-                .into_iter(),
-        );
+        // The state saved by a layer can only be restored by popping the layer.
+        if self
+            .layers
+            .last()
+            .map_or(false, |layer| layer.depth >= self.state.len())
+        {
+            return Err(Pierror::StackUnbalance);
+        }
 
+        self.pop_state();
         Ok(())
     }
 
     fn finish(&mut self) -> Result<(), Pierror> {
+        // Composite any layers that are still pushed.
+        let mut layer_result = Ok(());
+        while !self.layers.is_empty() {
+            if let Err(e) = self.pop_layer() {
+                layer_result = Err(e);
+            }
+        }
+
         self.source
             .context
             .flush()
             .map_err(|x| Pierror::BackendError(x.into()))
+            .and(layer_result)
     }
 
     fn transform(&mut self, transform: Affine) {
-        let slot = &mut self.state.last_mut().unwrap().transform;
-        *slot *= transform;
+        if let Some(state) = self.state.last_mut() {
+            state.transform *= transform;
+        }
     }
 
     fn make_image(
@@ -863,24 +1917,24 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         buf: &[u8],
         format: piet::ImageFormat,
     ) -> Result<Self::Image, Pierror> {
-        let tex = Texture::new(
-            &mut self.source.context,
-            self.device,
-            InterpolationMode::Bilinear,
-            RepeatStrategy::Color(piet::Color::TRANSPARENT),
-        )
-        .piet_err()?;
+        let source_format = format;
+        let (buf, size, format) = image::normalize_image_data((width, height), buf, format)?;
 
-        tex.write_texture(
+        // Try to pack the image into the atlas first.
+        if let Some(image) = self.source.image_atlas.allocate(
             &mut self.source.context,
             self.device,
             self.queue,
-            (width as u32, height as u32),
+            &self.source.deletions,
+            size,
             format,
-            Some(buf),
-        );
+            &buf,
+        )? {
+            return Ok(image.with_format(source_format));
+        }
 
-        Ok(Image::new(tex, Size::new(width as f64, height as f64)))
+        self.make_texture_image(size, &buf, format)
+            .map(|image| image.with_format(source_format))
     }
 
     fn draw_image(
@@ -899,6 +1953,15 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         dst_rect: impl Into<Rect>,
         interp: piet::InterpolationMode,
     ) {
+        // Make sure the image can be sampled with the interpolation mode.
+        let image = match self.sampled_image(image, interp) {
+            Ok(image) => image,
+            Err(e) => {
+                self.status = Err(e);
+                return;
+            }
+        };
+
         // Create a rectangle for the destination and a rectangle for UV.
         let pos_rect = dst_rect.into();
         let uv_rect = {
@@ -906,19 +1969,14 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
             let scale_y = 1.0 / image.size().height;
 
             let src_rect = src_rect.into();
-            Rect::new(
+            image.map_uv_rect(Rect::new(
                 src_rect.x0 * scale_x,
                 src_rect.y0 * scale_y,
                 src_rect.x1 * scale_x,
                 src_rect.y1 * scale_y,
-            )
+            ))
         };
 
-        // Set the interpolation mode.
-        image
-            .texture()
-            .set_interpolation(&mut self.source.context, self.device, interp);
-
         // Use this to draw the image.
         if let Err(e) = self.fill_rects(
             [TessRect {
@@ -932,30 +1990,55 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         }
     }
 
+    /// Capture an area of the render target into an image.
+    ///
+    /// `src_rect` is mapped through the current transform and the bitmap scale, and the
+    /// bounding box of the result is snapped outwards to whole pixels, so that every pixel it
+    /// touches is captured. That area is then clamped to the render target. The resulting image
+    /// is one image pixel per device pixel.
+    ///
+    /// Returns [`Pierror::InvalidInput`] if the area does not overlap the render target.
     fn capture_image_area(&mut self, src_rect: impl Into<Rect>) -> Result<Self::Image, Pierror> {
-        let src_rect = src_rect.into();
-        let src_size = src_rect.size();
-        let src_bitmap_size = Size::new(
-            src_size.width * self.bitmap_scale,
-            src_size.height * self.bitmap_scale,
-        );
+        // Figure out which device pixels the rectangle covers.
+        let transform = Affine::scale(self.bitmap_scale) * self.current_transform();
+        let device_rect = transform.transform_rect_bbox(src_rect.into());
+        let viewport = Rect::new(0.0, 0.0, self.size.0 as f64, self.size.1 as f64);
+        let device_rect = Rect::new(
+            device_rect.x0.floor(),
+            device_rect.y0.floor(),
+            device_rect.x1.ceil(),
+            device_rect.y1.ceil(),
+        )
+        .intersect(viewport);
+
+        // This also catches NaN rectangles.
+        if !(device_rect.width() > 0.0 && device_rect.height() > 0.0) {
+            return Err(Pierror::InvalidInput);
+        }
+
+        let offset = (device_rect.x0 as u32, device_rect.y0 as u32);
+        let size = (device_rect.width() as u32, device_rect.height() as u32);
 
         // Create a new texture to copy the image to.
         let image = {
             let texture = Texture::new(
                 &mut self.source.context,
                 self.device,
+                &self.source.deletions,
                 InterpolationMode::Bilinear,
                 RepeatStrategy::Repeat,
             )
             .piet_err()?;
 
-            Image::new(texture, src_bitmap_size)
+            Image::new(
+                texture,
+                Size::new(size.0 as f64, size.1 as f64),
+                size,
+                piet::ImageFormat::RgbaPremul,
+            )
         };
 
         // Capture the area in the texture.
-        let offset = (src_rect.x0 as u32, src_rect.y0 as u32);
-        let size = (src_size.width as u32, src_size.height as u32);
         self.source
             .context
             .capture_area(gpu_backend::AreaCapture {
@@ -986,12 +2069,16 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
 
         // Compute the blurred rectangle image.
         let (mask, rect_exp) = {
-            let mut mask = tiny_skia::Mask::new(width, height).unwrap();
+            let mut mask = leap!(
+                self,
+                tiny_skia::Mask::new(width, height).ok_or(()),
+                Pierror::InvalidInput
+            );
 
             let rect_exp = piet::util::compute_blurred_rect(
                 input_rect,
                 blur_radius,
-                width.try_into().unwrap(),
+                width as usize,
                 mask.data_mut(),
             );
 
@@ -999,8 +2086,16 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         };
 
         // Create an image using this mask.
-        let mut image = tiny_skia::Pixmap::new(width, height)
-            .expect("Pixmap width/height should be valid clipmask width/height");
+        let mut image = leap!(
+            self,
+            tiny_skia::Pixmap::new(width, height).ok_or(()),
+            Pierror::InvalidInput
+        );
+        let rect = leap!(
+            self,
+            tiny_skia::Rect::from_xywh(0., 0., width as f32, height as f32).ok_or(()),
+            Pierror::InvalidInput
+        );
         let shader = match brush.make_brush(self, || input_rect).to_shader() {
             Some(shader) => shader,
             None => {
@@ -1010,7 +2105,7 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
         };
         image.fill(tiny_skia::Color::TRANSPARENT);
         image.fill_rect(
-            tiny_skia::Rect::from_xywh(0., 0., width as f32, height as f32).unwrap(),
+            rect,
             &tiny_skia::Paint {
                 shader,
                 ..Default::default()
@@ -1026,14 +2121,16 @@ impl<C: GpuContext + ?Sized> piet::RenderContext for RenderContext<'_, '_, '_, C
                 width as usize,
                 height as usize,
                 image.data(),
-                piet::ImageFormat::RgbaSeparate
+                piet::ImageFormat::RgbaPremul
             )
         );
         self.draw_image(&image, rect_exp, piet::InterpolationMode::Bilinear);
     }
 
     fn current_transform(&self) -> Affine {
-        self.state.last().unwrap().transform
+        self.state
+            .last()
+            .map_or(Affine::IDENTITY, |state| state.transform)
     }
 }
 
@@ -1043,7 +2140,7 @@ struct TemporarilyIgnoreState<'this, 'a, 'b, 'c, C: GpuContext + ?Sized>(
 
 impl<C: GpuContext + ?Sized> Drop for TemporarilyIgnoreState<'_, '_, '_, '_, C> {
     fn drop(&mut self) {
-        self.0.ignore_state = false;
+        self.0.ignore_state = None;
     }
 }
 
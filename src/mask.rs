@@ -19,26 +19,26 @@
 //! The mask used for clipping.
 
 use super::gpu_backend::{GpuContext, RepeatStrategy};
-use super::resources::Texture;
-use super::shape_to_skia_path;
+use super::resources::{DeletionQueue, Texture};
+use super::{shape_to_skia_path, ResultExt};
 
 use piet::kurbo::Shape;
-use piet::InterpolationMode;
+use piet::{Error as Pierror, InterpolationMode};
 
 use std::{fmt, mem};
 
 use tiny_skia as ts;
 use ts::{FillRule, Mask as ClipMask, PathBuilder, PixmapMut};
 
-// TODO: Adjust based on real-world data.
-const TOO_MANY_TEXTURES: usize = 1024;
+/// The default number of bytes of idle mask textures to keep around for reuse.
+pub(crate) const DEFAULT_MASK_BUDGET: usize = 64 * 1024 * 1024;
 
 /// The context for creating and modifying masks.
 pub(crate) struct MaskContext<C: GpuContext + ?Sized> {
     /// A scratch buffer for rendering masks into.
     mask_render_buffer: Vec<u32>,
 
-    /// List of GPU textures to re-use.
+    /// List of GPU textures to re-use, from least to most recently used.
     gpu_textures: Vec<SizedTexture<C>>,
 
     /// The GPU textures currently in use.
@@ -46,6 +46,12 @@ pub(crate) struct MaskContext<C: GpuContext + ?Sized> {
 
     /// Cached path builder for drawing into the mask.
     path_builder: PathBuilder,
+
+    /// The repeat strategy to create mask textures with.
+    repeat: RepeatStrategy,
+
+    /// The maximum number of bytes of textures to keep in `gpu_textures`.
+    budget: usize,
 }
 
 struct SizedTexture<C: GpuContext + ?Sized> {
@@ -53,6 +59,13 @@ struct SizedTexture<C: GpuContext + ?Sized> {
     size: (u32, u32),
 }
 
+impl<C: GpuContext + ?Sized> SizedTexture<C> {
+    /// The number of bytes that this texture takes up on the GPU.
+    fn bytes(&self) -> usize {
+        self.size.0 as usize * self.size.1 as usize * 4
+    }
+}
+
 /// A mask that can be clipped into.
 pub(crate) struct Mask<C: GpuContext + ?Sized> {
     /// The underlying tiny-skia mask.
@@ -120,13 +133,15 @@ impl<C: GpuContext + ?Sized> MaskState<C> {
 }
 
 impl<C: GpuContext + ?Sized> MaskContext<C> {
-    /// Create a new, empty mask context.
-    pub(crate) fn new() -> Self {
+    /// Create a new, empty mask context whose textures use the given repeat strategy.
+    pub(crate) fn new(repeat: RepeatStrategy) -> Self {
         Self {
             mask_render_buffer: Vec::new(),
             gpu_textures: Vec::new(),
             used_textures: Vec::new(),
             path_builder: PathBuilder::new(),
+            repeat,
+            budget: DEFAULT_MASK_BUDGET,
         }
     }
 
@@ -136,7 +151,17 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
         let path = {
             let mut builder = mem::take(&mut self.path_builder);
             shape_to_skia_path(&mut builder, shape, tolerance);
-            builder.finish().expect("path builder failed")
+            builder.finish()
+        };
+
+        // An empty path covers nothing, so nothing is visible through the mask.
+        let path = match path {
+            Some(path) => path,
+            None => {
+                mask.mask.clear();
+                mask.state.dirty();
+                return;
+            }
         };
 
         if mask.state.is_empty() {
@@ -160,9 +185,12 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
         context: &mut C,
         device: &C::Device,
         queue: &C::Queue,
-    ) -> &'a Texture<C> {
-        self.upload_mask(mask, context, device, queue);
-        mask.state.texture().expect("mask texture")
+        deletions: &DeletionQueue<C>,
+    ) -> Result<&'a Texture<C>, Pierror> {
+        self.upload_mask(mask, context, device, queue, deletions)?;
+        mask.state
+            .texture()
+            .ok_or_else(|| Pierror::BackendError("Mask has no texture.".into()))
     }
 
     /// Indicate that a texture has been used in this operation.
@@ -175,9 +203,44 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
     /// Indicate that the GPU queue has been flushed.
     pub(crate) fn gpu_flushed(&mut self) {
         self.gpu_textures.append(&mut self.used_textures);
+        self.evict();
+    }
+
+    /// Drop all of the cached textures.
+    pub(crate) fn clear(&mut self) {
+        self.gpu_textures.clear();
+        self.used_textures.clear();
+    }
+
+    /// Drop the cached textures and scratch memory that don't fit a render target of the given
+    /// size.
+    pub(crate) fn resize(&mut self, size: (u32, u32)) {
+        self.gpu_textures.retain(|texture| texture.size == size);
+        self.used_textures.retain(|texture| texture.size == size);
+        self.mask_render_buffer = Vec::new();
+    }
 
-        // If we have *a lot* of masks, get rid of some of them.
-        self.gpu_textures.truncate(TOO_MANY_TEXTURES);
+    /// Get the maximum number of bytes of idle textures to keep around.
+    pub(crate) fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Set the maximum number of bytes of idle textures to keep around.
+    pub(crate) fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    /// Drop the least recently used idle textures until they fit in the budget.
+    fn evict(&mut self) {
+        let mut total: usize = self.gpu_textures.iter().map(SizedTexture::bytes).sum();
+        let mut evicted = 0;
+        while total > self.budget {
+            total -= self.gpu_textures[evicted].bytes();
+            evicted += 1;
+        }
+
+        self.gpu_textures.drain(..evicted);
     }
 
     /// Upload a mask into a texture.
@@ -187,14 +250,17 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
         context: &mut C,
         device: &C::Device,
         queue: &C::Queue,
-    ) {
+        deletions: &DeletionQueue<C>,
+    ) -> Result<(), Pierror> {
         if mask.state.is_empty() {
-            unreachable!("uploading empty mask");
+            return Err(Pierror::BackendError(
+                "Tried to upload an empty mask.".into(),
+            ));
         }
 
         if !mask.state.is_dirty() {
             // No need to change anything.
-            return;
+            return Ok(());
         }
 
         // Create a pixmap to render into, using our scratch space.
@@ -209,14 +275,16 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
             width,
             height,
         )
-        .expect("If the width/height is valid for the mask, it should work for the pixmap as well");
+        .ok_or_else(|| Pierror::BackendError("Failed to create pixmap for mask.".into()))?;
+        let rect = ts::Rect::from_xywh(0., 0., width as f32, height as f32)
+            .ok_or(Pierror::InvalidInput)?;
 
         // Clear the pixmap with a black color.
         pixmap.fill(ts::Color::TRANSPARENT);
 
         // Render the mask into the pixmap.
         pixmap.fill_rect(
-            ts::Rect::from_xywh(0., 0., width as f32, height as f32).expect("valid rect"),
+            rect,
             &ts::Paint {
                 shader: ts::Shader::SolidColor(ts::Color::WHITE),
                 ..Default::default()
@@ -226,43 +294,50 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
         );
 
         // Either create a new GPU texture or re-use an older one.
-        let texture = mask
-            .state
-            .take_texture()
-            .or_else(|| {
-                // Look for a texture with the same size.
-                self.gpu_textures
-                    .iter()
-                    .rposition(|tex| tex.size == (width, height))
-                    .map(|idx| self.gpu_textures.swap_remove(idx))
-            })
-            .unwrap_or_else(|| {
+        let reused = mask.state.take_texture().or_else(|| {
+            // Look for a texture with the same size.
+            self.gpu_textures
+                .iter()
+                .rposition(|tex| tex.size == (width, height))
+                .map(|idx| self.gpu_textures.remove(idx))
+        });
+        let texture = match reused {
+            Some(texture) => texture,
+            None => {
                 let texture = Texture::new(
                     context,
                     device,
+                    deletions,
                     InterpolationMode::Bilinear,
-                    RepeatStrategy::Color(piet::Color::TRANSPARENT),
+                    self.repeat,
                 )
-                .expect("failed to create texture");
+                .piet_err()?;
                 let size = (width, height);
 
                 SizedTexture { texture, size }
-            });
+            }
+        };
 
         // Upload the pixmap to the texture.
-        texture.texture.write_texture(
+        let written = texture.texture.write_texture(
             context,
             device,
             queue,
             (width, height),
-            piet::ImageFormat::RgbaSeparate,
+            piet::ImageFormat::RgbaPremul,
             Some(pixmap.data_mut()),
         );
 
         // Put the texture back.
         //
-        // This also marks the texture as non-dirty.
+        // This also marks the texture as non-dirty, unless the upload failed.
+        if let Err(e) = written {
+            mask.state = MaskState::DirtyWithTexture(texture);
+            return Err(Pierror::BackendError(e.into()));
+        }
         mask.state = MaskState::Clean(texture);
+
+        Ok(())
     }
 
     /// Reclaim the textures of a set of masks.
@@ -275,11 +350,13 @@ impl<C: GpuContext + ?Sized> MaskContext<C> {
 
 impl<C: GpuContext + ?Sized> Mask<C> {
     /// Create a new mask with the given size.
-    pub(crate) fn new(width: u32, height: u32) -> Self {
-        Self {
-            mask: ClipMask::new(width, height).expect("failed to create mask"),
+    ///
+    /// Returns [`Pierror::InvalidInput`] if either dimension is zero.
+    pub(crate) fn new(width: u32, height: u32) -> Result<Self, Pierror> {
+        Ok(Self {
+            mask: ClipMask::new(width, height).ok_or(Pierror::InvalidInput)?,
             state: MaskState::Empty,
-        }
+        })
     }
 }
 
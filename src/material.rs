@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Backend-specific materials for custom fill effects.
+
+use piet::kurbo::{Affine, Point};
+
+use std::rc::Rc;
+
+/// A custom fill effect that is shaded by the backend.
+///
+/// A material is identified by an `id` that the application and the backend agree on, along
+/// with a list of parameters that are forwarded to the backend as-is. The renderer tessellates,
+/// transforms and clips shapes filled with a material as usual, and passes the material to
+/// [`GpuContext::push_buffers`] through [`BufferPush::material`], leaving the shading to the
+/// backend.
+///
+/// Backends list the identifiers of the materials that they support in
+/// [`Capabilities::materials`], so applications can check for them up front with
+/// [`Source::capabilities`]. Use [`RenderContext::material_brush`] to create a brush that fills
+/// shapes with a material.
+///
+/// Blurred rectangles are rasterized on the CPU, so filling one with a material brush fills it
+/// with the brush's tint instead, or with white if the brush has no tint.
+///
+/// [`Capabilities::materials`]: crate::Capabilities::materials
+/// [`Source::capabilities`]: crate::Source::capabilities
+/// [`GpuContext::push_buffers`]: crate::GpuContext::push_buffers
+/// [`BufferPush::material`]: crate::gpu_types::BufferPush::material
+/// [`RenderContext::material_brush`]: crate::RenderContext::material_brush
+#[derive(Debug, Clone, PartialEq)]
+pub struct Material {
+    /// The identifier of the material.
+    id: u32,
+
+    /// The parameters of the material.
+    params: Rc<[f32]>,
+
+    /// The transform from user space into material coordinates.
+    transform: Affine,
+}
+
+impl Material {
+    /// Create a new material with the given identifier and parameters.
+    pub fn new(id: u32, params: impl Into<Rc<[f32]>>) -> Self {
+        Self {
+            id,
+            params: params.into(),
+            transform: Affine::IDENTITY,
+        }
+    }
+
+    /// Get a copy of this material that maps user space into material coordinates with
+    /// `transform`.
+    ///
+    /// By default, material coordinates are the same as the coordinates of the shape being
+    /// filled, before the render context's transform is applied.
+    pub fn with_transform(mut self, transform: Affine) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Get the identifier of this material.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Get the parameters of this material.
+    pub fn params(&self) -> &[f32] {
+        &self.params
+    }
+
+    /// Get the transform from user space into material coordinates.
+    pub fn transform(&self) -> Affine {
+        self.transform
+    }
+
+    /// Map a point in user space into material coordinates.
+    pub(crate) fn map_point(&self, point: Point) -> Point {
+        self.transform * point
+    }
+}
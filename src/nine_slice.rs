@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Splitting images into nine slices for drawing scalable UI elements.
+
+use piet::kurbo::{Insets, Rect, Size};
+
+/// The most tiles that a single edge or the center is split into along one axis.
+///
+/// This keeps tiny source areas from producing an unbounded number of rectangles.
+const MAX_TILES: usize = 256;
+
+/// A span of the destination along one axis, paired with the span of the image drawn into it.
+type Span = ((f64, f64), (f64, f64));
+
+/// How the edges and center of a nine-slice image fill their area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum NineSliceMode {
+    /// Stretch the edges and center to fill their area.
+    #[default]
+    Stretch,
+
+    /// Repeat the edges and center at their original size, cutting off the last tile.
+    Tile,
+}
+
+/// Split an image into the slices needed to draw it into `dst_rect`.
+///
+/// Returns pairs of destination rectangles and source rectangles, with the source rectangles
+/// in the image's coordinate space. Returns `None` if the insets do not fit in the image.
+pub(crate) fn nine_slices(
+    image_size: Size,
+    insets: Insets,
+    dst_rect: Rect,
+    mode: NineSliceMode,
+) -> Option<Vec<(Rect, Rect)>> {
+    let Insets { x0, y0, x1, y1 } = insets;
+    let valid = [x0, y0, x1, y1].iter().all(|inset| *inset >= 0.0)
+        && x0 + x1 <= image_size.width
+        && y0 + y1 <= image_size.height;
+    if !valid {
+        return None;
+    }
+
+    let dst_rect = dst_rect.abs();
+    let tile = matches!(mode, NineSliceMode::Tile);
+    let columns = axis(
+        (0.0, x0, image_size.width - x1, image_size.width),
+        (dst_rect.x0, dst_rect.x1),
+        (x0, x1),
+        tile,
+    );
+    let rows = axis(
+        (0.0, y0, image_size.height - y1, image_size.height),
+        (dst_rect.y0, dst_rect.y1),
+        (y0, y1),
+        tile,
+    );
+
+    let mut slices = Vec::new();
+    for &((dst_y0, dst_y1), (src_y0, src_y1)) in &rows {
+        for &((dst_x0, dst_x1), (src_x0, src_x1)) in &columns {
+            slices.push((
+                Rect::new(dst_x0, dst_y0, dst_x1, dst_y1),
+                Rect::new(src_x0, src_y0, src_x1, src_y1),
+            ));
+        }
+    }
+
+    Some(slices)
+}
+
+/// Split one axis of the image into destination and source spans.
+fn axis(
+    (src0, src1, src2, src3): (f64, f64, f64, f64),
+    (dst_start, dst_end): (f64, f64),
+    (start_inset, end_inset): (f64, f64),
+    tile: bool,
+) -> Vec<Span> {
+    // Keep the corners at their original size, unless they don't fit.
+    let length = dst_end - dst_start;
+    let scale = if start_inset + end_inset > length {
+        length / (start_inset + end_inset)
+    } else {
+        1.0
+    };
+    let dst1 = dst_start + start_inset * scale;
+    let dst2 = dst_end - end_inset * scale;
+
+    let mut spans = vec![((dst_start, dst1), (src0, src1))];
+    if tile {
+        tiles(&mut spans, (dst1, dst2), (src1, src2));
+    } else {
+        spans.push(((dst1, dst2), (src1, src2)));
+    }
+    spans.push(((dst2, dst_end), (src2, src3)));
+
+    // Empty spans would only produce invisible rectangles.
+    spans.retain(|((start, end), _)| end > start);
+    spans
+}
+
+/// Repeat a source span across a destination span.
+fn tiles(
+    spans: &mut Vec<Span>,
+    (dst_start, dst_end): (f64, f64),
+    (src_start, src_end): (f64, f64),
+) {
+    let tile_length = src_end - src_start;
+    let length = dst_end - dst_start;
+    if tile_length <= 0.0 || length / tile_length > MAX_TILES as f64 {
+        spans.push(((dst_start, dst_end), (src_start, src_end)));
+        return;
+    }
+
+    let mut position = dst_start;
+    while position < dst_end {
+        let end = (position + tile_length).min(dst_end);
+        spans.push(((position, end), (src_start, src_start + (end - position))));
+        position = end;
+    }
+}
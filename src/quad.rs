@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Mapping images onto arbitrary quadrilaterals.
+
+use super::gpu_backend::Vertex;
+
+use piet::kurbo::{Point, Rect};
+
+/// The number of cells along each side of the grid used to approximate a non-affine mapping.
+const SUBDIVISIONS: u32 = 16;
+
+/// How an image is mapped onto a quadrilateral.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[non_exhaustive]
+pub enum QuadMapping {
+    /// Interpolate the image's coordinates bilinearly between the corners.
+    ///
+    /// Straight lines in the image may appear curved if the quadrilateral is not a
+    /// parallelogram.
+    #[default]
+    Bilinear,
+
+    /// Map the image as if the quadrilateral were a rectangle viewed in perspective.
+    ///
+    /// This keeps straight lines straight, but the quadrilateral must be convex. The image's
+    /// coordinates are passed to the backend as homogeneous coordinates through [`Vertex::q`],
+    /// so the mapping is exact across the whole quadrilateral.
+    Perspective,
+}
+
+/// A mapping from the unit square to a quadrilateral.
+enum Mapping {
+    /// Bilinear interpolation between the four corners.
+    Bilinear([Point; 4]),
+
+    /// A projective transform, stored as the rows of a 3x3 matrix.
+    Projective([[f64; 3]; 3]),
+}
+
+impl Mapping {
+    /// Create the mapping for a quadrilateral.
+    ///
+    /// Returns `None` if the quadrilateral cannot be mapped in perspective.
+    fn new(corners: [Point; 4], mode: QuadMapping) -> Option<Self> {
+        match mode {
+            QuadMapping::Bilinear => Some(Self::Bilinear(corners)),
+            QuadMapping::Perspective => projective(corners).map(Self::Projective),
+        }
+    }
+
+    /// Map a point in the unit square onto the quadrilateral.
+    ///
+    /// Returns the point along with its homogeneous `w` coordinate, which is `1.0` unless the
+    /// mapping is projective.
+    fn map(&self, u: f64, v: f64) -> Option<(Point, f64)> {
+        match self {
+            Self::Bilinear([p0, p1, p2, p3]) => {
+                let top = p0.lerp(*p1, u);
+                let bottom = p3.lerp(*p2, u);
+                Some((top.lerp(bottom, v), 1.0))
+            }
+            Self::Projective([x, y, w]) => {
+                let w = w[0] * u + w[1] * v + w[2];
+                if w <= f64::EPSILON {
+                    return None;
+                }
+
+                Some((
+                    Point::new(
+                        (x[0] * u + x[1] * v + x[2]) / w,
+                        (y[0] * u + y[1] * v + y[2]) / w,
+                    ),
+                    w,
+                ))
+            }
+        }
+    }
+}
+
+/// Compute the projective transform that maps the unit square onto a quadrilateral.
+fn projective([p0, p1, p2, p3]: [Point; 4]) -> Option<[[f64; 3]; 3]> {
+    let (dx1, dy1) = (p1.x - p2.x, p1.y - p2.y);
+    let (dx2, dy2) = (p3.x - p2.x, p3.y - p2.y);
+    let (dx3, dy3) = (p0.x - p1.x + p2.x - p3.x, p0.y - p1.y + p2.y - p3.y);
+
+    let det = dx1 * dy2 - dx2 * dy1;
+    if det.abs() <= f64::EPSILON {
+        return None;
+    }
+
+    let g = (dx3 * dy2 - dx2 * dy3) / det;
+    let h = (dx1 * dy3 - dx3 * dy1) / det;
+
+    Some([
+        [p1.x - p0.x + g * p1.x, p3.x - p0.x + h * p3.x, p0.x],
+        [p1.y - p0.y + g * p1.y, p3.y - p0.y + h * p3.y, p0.y],
+        [g, h, 1.0],
+    ])
+}
+
+/// Build a mesh that maps `uv_rect` onto the quadrilateral formed by `corners`.
+///
+/// The corners are in the order top-left, top-right, bottom-right, bottom-left, relative to
+/// the image. Returns `None` if the quadrilateral cannot be mapped with the given mode.
+pub(crate) fn quad_mesh(
+    corners: [Point; 4],
+    uv_rect: Rect,
+    mode: QuadMapping,
+    color: piet::Color,
+) -> Option<(Vec<Vertex>, Vec<u32>)> {
+    let mapping = Mapping::new(corners, mode)?;
+
+    // Parallelograms map affinely and perspective mappings use homogeneous coordinates, so they
+    // only need two triangles. Bilinear mappings are approximated with a grid.
+    let [p0, p1, p2, p3] = corners;
+    let is_parallelogram = ((p0 - p1) - (p3 - p2)).hypot2() <= f64::EPSILON;
+    let cells = if is_parallelogram || mode == QuadMapping::Perspective {
+        1
+    } else {
+        SUBDIVISIONS
+    };
+
+    let (r, g, b, a) = color.as_rgba8();
+    let mut vertices = Vec::with_capacity(((cells + 1) * (cells + 1)) as usize);
+    for row in 0..=cells {
+        let v = row as f64 / cells as f64;
+        for column in 0..=cells {
+            let u = column as f64 / cells as f64;
+            let (pos, w) = mapping.map(u, v)?;
+
+            // Interpolating `uv / w` and `1 / w` linearly in screen space is exact.
+            let q = w.recip();
+            vertices.push(Vertex {
+                pos: [pos.x as f32, pos.y as f32],
+                uv: [
+                    ((uv_rect.x0 + u * uv_rect.width()) * q) as f32,
+                    ((uv_rect.y0 + v * uv_rect.height()) * q) as f32,
+                ],
+                q: q as f32,
+                color: [r, g, b, a],
+            });
+        }
+    }
+
+    let stride = cells + 1;
+    let mut indices = Vec::with_capacity((cells * cells * 6) as usize);
+    for row in 0..cells {
+        for column in 0..cells {
+            let top_left = row * stride + column;
+            let bottom_left = top_left + stride;
+            indices.extend_from_slice(&[
+                top_left,
+                top_left + 1,
+                bottom_left + 1,
+                top_left,
+                bottom_left + 1,
+                bottom_left,
+            ]);
+        }
+    }
+
+    Some((vertices, indices))
+}
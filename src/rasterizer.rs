@@ -23,6 +23,7 @@ use super::stroke::StrokeBuffer;
 use super::ResultExt;
 
 use arrayvec::ArrayVec;
+use tinyvec::TinyVec;
 
 use tiny_skia::PathSegment;
 
@@ -35,6 +36,9 @@ use lyon_tessellation::{
 use piet::kurbo::{BezPath, PathEl, Point, Rect, Shape};
 use piet::{Color, Error as Pierror, LineCap, LineJoin};
 
+use std::borrow::Cow;
+use std::mem;
+
 pub(crate) struct Rasterizer {
     /// Buffers for tessellating the path.
     buffers: VertexBuffers<Vertex, u32>,
@@ -90,21 +94,25 @@ impl Rasterizer {
                 Vertex {
                     pos: [cast(pos_rect.x0), cast(pos_rect.y0)],
                     uv: [cast(uv_rect.x0), cast(uv_rect.y0)],
+                    q: 1.0,
                     color,
                 },
                 Vertex {
                     pos: [cast(pos_rect.x1), cast(pos_rect.y0)],
                     uv: [cast(uv_rect.x1), cast(uv_rect.y0)],
+                    q: 1.0,
                     color,
                 },
                 Vertex {
                     pos: [cast(pos_rect.x1), cast(pos_rect.y1)],
                     uv: [cast(uv_rect.x1), cast(uv_rect.y1)],
+                    q: 1.0,
                     color,
                 },
                 Vertex {
                     pos: [cast(pos_rect.x0), cast(pos_rect.y1)],
                     uv: [cast(uv_rect.x0), cast(uv_rect.y1)],
+                    q: 1.0,
                     color,
                 },
             ]
@@ -123,6 +131,21 @@ impl Rasterizer {
         }));
     }
 
+    /// Add a pre-tessellated triangle mesh.
+    ///
+    /// The indices are relative to the start of `vertices`.
+    pub(crate) fn fill_mesh(
+        &mut self,
+        vertices: impl IntoIterator<Item = Vertex>,
+        indices: &[u32],
+    ) {
+        let base = self.buffers.vertices.len() as u32;
+        self.buffers.vertices.extend(vertices);
+        self.buffers
+            .indices
+            .extend(indices.iter().map(|&index| base + index));
+    }
+
     /// Tessellate a filled shape.
     pub(crate) fn fill_shape(
         &mut self,
@@ -234,6 +257,70 @@ pub(crate) struct TessRect {
     pub(crate) color: Color,
 }
 
+/// A part of a mesh that can be drawn on its own.
+pub(crate) type MeshChunk<'a> = (Cow<'a, [Vertex]>, Cow<'a, [u32]>);
+
+/// Split a mesh into chunks that each have at most `max_vertices` vertices.
+///
+/// Triangles that refer to vertices that do not exist are dropped from the split chunks.
+pub(crate) fn split_mesh<'a>(
+    vertices: &'a [Vertex],
+    indices: &'a [u32],
+    max_vertices: usize,
+) -> TinyVec<[MeshChunk<'a>; 1]> {
+    let mut chunks = TinyVec::new();
+    if vertices.len() <= max_vertices {
+        chunks.push((Cow::Borrowed(vertices), Cow::Borrowed(indices)));
+        return chunks;
+    }
+
+    // A chunk needs room for at least one triangle.
+    let max_vertices = max_vertices.max(3);
+
+    // Maps indices into `vertices` to indices into the current chunk.
+    let mut remap = vec![u32::MAX; vertices.len()];
+    let mut used = Vec::new();
+    let mut chunk_vertices = Vec::new();
+    let mut chunk_indices = Vec::new();
+
+    for triangle in indices.chunks_exact(3) {
+        if triangle.iter().any(|&i| i as usize >= vertices.len()) {
+            continue;
+        }
+
+        // Start a new chunk if this triangle's vertices won't fit.
+        let new_vertices = triangle
+            .iter()
+            .filter(|&&i| remap[i as usize] == u32::MAX)
+            .count();
+        if chunk_vertices.len() + new_vertices > max_vertices {
+            for i in used.drain(..) {
+                remap[i] = u32::MAX;
+            }
+            chunks.push((
+                Cow::Owned(mem::take(&mut chunk_vertices)),
+                Cow::Owned(mem::take(&mut chunk_indices)),
+            ));
+        }
+
+        for &i in triangle {
+            let slot = &mut remap[i as usize];
+            if *slot == u32::MAX {
+                *slot = chunk_vertices.len() as u32;
+                chunk_vertices.push(vertices[i as usize]);
+                used.push(i as usize);
+            }
+            chunk_indices.push(*slot);
+        }
+    }
+
+    if !chunk_indices.is_empty() {
+        chunks.push((Cow::Owned(chunk_vertices), Cow::Owned(chunk_indices)));
+    }
+
+    chunks
+}
+
 fn shape_to_lyon_path(shape: &impl Shape, tolerance: f64) -> impl Iterator<Item = PathEvent> + '_ {
     use std::iter::Fuse;
 
@@ -252,10 +339,34 @@ fn shape_to_lyon_path(shape: &impl Shape, tolerance: f64) -> impl Iterator<Item
         /// The first point of the current subpath.
         first: Option<Point>,
 
+        /// The point to start from if a segment follows a closed subpath.
+        resume: Option<Point>,
+
         // Whether or not we need to close the path.
         needs_close: bool,
     }
 
+    impl<I> PathConverter<I> {
+        /// Get the point that a segment ending at `to` starts from.
+        ///
+        /// If there is no current subpath, this begins a new one at the start of the last
+        /// closed subpath, or at `to` if there wasn't one.
+        fn segment_start(&mut self, to: Point, events: &mut ArrayVec<PathEvent, 2>) -> Point {
+            self.needs_close = true;
+            match self.last.replace(to) {
+                Some(from) => from,
+                None => {
+                    let from = self.resume.take().unwrap_or(to);
+                    self.first = Some(from);
+                    events.push(Event::Begin {
+                        at: convert_point(from),
+                    });
+                    from
+                }
+            }
+        }
+    }
+
     impl<I: Iterator<Item = PathEl>> Iterator for PathConverter<I> {
         type Item = ArrayVec<PathEvent, 2>;
 
@@ -293,6 +404,7 @@ fn shape_to_lyon_path(shape: &impl Shape, tolerance: f64) -> impl Iterator<Item
                     // Set the first point.
                     self.first = Some(pt);
                     self.last = Some(pt);
+                    self.resume = None;
 
                     let mut v = ArrayVec::new();
                     v.extend(close);
@@ -303,41 +415,49 @@ fn shape_to_lyon_path(shape: &impl Shape, tolerance: f64) -> impl Iterator<Item
                 }
 
                 PathEl::LineTo(pt) => {
-                    self.needs_close = true;
-                    let from = self.last.replace(pt).expect("last point should be set");
+                    let mut v = ArrayVec::new();
+                    let from = self.segment_start(pt, &mut v);
 
-                    Some(one(Event::Line {
+                    v.push(Event::Line {
                         from: convert_point(from),
                         to: convert_point(pt),
-                    }))
+                    });
+                    Some(v)
                 }
 
                 PathEl::QuadTo(ctrl1, pt) => {
-                    self.needs_close = true;
-                    let from = self.last.replace(pt).expect("last point should be set");
+                    let mut v = ArrayVec::new();
+                    let from = self.segment_start(pt, &mut v);
 
-                    Some(one(Event::Quadratic {
+                    v.push(Event::Quadratic {
                         from: convert_point(from),
                         ctrl: convert_point(ctrl1),
                         to: convert_point(pt),
-                    }))
+                    });
+                    Some(v)
                 }
 
                 PathEl::CurveTo(ctrl1, ctrl2, pt) => {
-                    self.needs_close = true;
-                    let from = self.last.replace(pt).expect("last point should be set");
+                    let mut v = ArrayVec::new();
+                    let from = self.segment_start(pt, &mut v);
 
-                    Some(one(Event::Cubic {
+                    v.push(Event::Cubic {
                         from: convert_point(from),
                         ctrl1: convert_point(ctrl1),
                         ctrl2: convert_point(ctrl2),
                         to: convert_point(pt),
-                    }))
+                    });
+                    Some(v)
                 }
 
                 PathEl::ClosePath => {
+                    // Segments after the close continue from the start of this subpath.
+                    let first = self.first;
                     let mut v = ArrayVec::new();
                     v.extend(close(self, true));
+                    if first.is_some() {
+                        self.resume = first;
+                    }
                     Some(v)
                 }
             }
@@ -348,6 +468,7 @@ fn shape_to_lyon_path(shape: &impl Shape, tolerance: f64) -> impl Iterator<Item
         iter: shape.path_elements(tolerance).fuse(),
         last: None,
         first: None,
+        resume: None,
         needs_close: false,
     }
     .flatten()
@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: LGPL-3.0-or-later OR MPL-2.0
+// This file is a part of `piet-hardware`.
+//
+// `piet-hardware` is free software: you can redistribute it and/or modify it under the
+// terms of either:
+//
+// * GNU Lesser General Public License as published by the Free Software Foundation, either
+//   version 3 of the License, or (at your option) any later version.
+// * Mozilla Public License as published by the Mozilla Foundation, version 2.
+//
+// `piet-hardware` is distributed in the hope that it will be useful, but WITHOUT ANY
+// WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR
+// PURPOSE. See the GNU Lesser General Public License or the Mozilla Public License for more
+// details.
+//
+// You should have received a copy of the GNU Lesser General Public License and the Mozilla
+// Public License along with `piet-hardware`. If not, see <https://www.gnu.org/licenses/>.
+
+//! Reading the contents of images back into CPU memory.
+
+use super::gpu_backend::{GpuContext, TextureRead};
+use super::image::Image;
+use super::ResultExt;
+
+use piet::{Error as Pierror, ImageBuf};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+/// The result of a readback, once it has been resolved.
+type Slot = RefCell<Option<Result<ImageBuf, Pierror>>>;
+
+/// A handle to the pixels of an image that will be read back once the GPU has been flushed.
+///
+/// This is returned by [`RenderContext::read_image_deferred`]. The readback is resolved when
+/// [`Source::gpu_flushed`] is called.
+///
+/// [`RenderContext::read_image_deferred`]: crate::RenderContext::read_image_deferred
+/// [`Source::gpu_flushed`]: crate::Source::gpu_flushed
+pub struct ImageReadback {
+    /// The slot that the result is written into.
+    slot: Rc<Slot>,
+}
+
+impl fmt::Debug for ImageReadback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageReadback")
+            .field("ready", &self.is_ready())
+            .finish()
+    }
+}
+
+impl ImageReadback {
+    /// Tell whether the readback has been resolved.
+    pub fn is_ready(&self) -> bool {
+        self.slot.borrow().is_some()
+    }
+
+    /// Take the result of the readback, if it has been resolved.
+    ///
+    /// This returns `None` if the readback has not been resolved yet, or if the result has
+    /// already been taken.
+    pub fn take(&self) -> Option<Result<ImageBuf, Pierror>> {
+        self.slot.borrow_mut().take()
+    }
+}
+
+/// A readback that is waiting for the GPU to finish drawing.
+pub(crate) struct PendingReadback<C: GpuContext + ?Sized> {
+    /// The image to read from.
+    image: Image<C>,
+
+    /// The slot to write the result into, if the handle is still alive.
+    slot: Weak<Slot>,
+}
+
+impl<C: GpuContext + ?Sized> PendingReadback<C> {
+    /// Create a new pending readback, along with its handle.
+    pub(crate) fn new(image: &Image<C>) -> (Self, ImageReadback) {
+        let slot = Rc::new(RefCell::new(None));
+        let pending = Self {
+            image: image.clone(),
+            slot: Rc::downgrade(&slot),
+        };
+
+        (pending, ImageReadback { slot })
+    }
+
+    /// Read back the image and hand the result to the handle.
+    pub(crate) fn resolve(self, context: &mut C, device: &C::Device, queue: &C::Queue) {
+        // Don't bother reading the image if no one is waiting for it.
+        if let Some(slot) = self.slot.upgrade() {
+            let result = read_image(context, device, queue, &self.image);
+            *slot.borrow_mut() = Some(result);
+        }
+    }
+}
+
+/// Read the pixels of an image into CPU memory.
+pub(crate) fn read_image<C: GpuContext + ?Sized>(
+    context: &mut C,
+    device: &C::Device,
+    queue: &C::Queue,
+    image: &Image<C>,
+) -> Result<ImageBuf, Pierror> {
+    let (width, height) = image.pixel_size();
+    let mut data = vec![0u8; width as usize * height as usize * 4];
+
+    if width > 0 && height > 0 {
+        let supported = context
+            .read_texture(TextureRead {
+                device,
+                queue,
+                texture: image.texture().resource(),
+                offset: image.offset(),
+                size: (width, height),
+                data: &mut data,
+            })
+            .piet_err()?;
+
+        if !supported {
+            return Err(Pierror::NotSupported);
+        }
+    }
+
+    Ok(ImageBuf::from_raw(
+        data,
+        piet::ImageFormat::RgbaPremul,
+        width as usize,
+        height as usize,
+    ))
+}
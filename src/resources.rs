@@ -19,8 +19,14 @@
 //! Defines useful resource wrappers.
 
 use super::gpu_backend::{GpuContext, RepeatStrategy, Vertex};
+use super::gradient;
+use super::ResultExt;
 
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::fmt;
+use std::mem;
+use std::rc::Rc;
 
 use piet::kurbo::{Size, Vec2};
 use piet::{
@@ -29,10 +35,14 @@ use piet::{
 use tiny_skia::{Paint, Pixmap, Shader};
 
 macro_rules! define_resource_wrappers {
-    ($($name:ident($res:ident)),* $(,)?) => {
+    ($($name:ident($res:ident, $deleted:ident)),* $(,)?) => {
         $(
             pub(crate) struct $name<C: GpuContext + ?Sized> {
-                resource: C::$res,
+                /// The resource, which is only `None` once it has been released.
+                resource: Option<C::$res>,
+
+                /// The queue to push the resource into once it is dropped.
+                deletions: DeletionQueue<C>,
             }
 
             impl<C: GpuContext + ?Sized> fmt::Debug for $name<C> {
@@ -42,13 +52,31 @@ macro_rules! define_resource_wrappers {
                 }
             }
 
+            impl<C: GpuContext + ?Sized> Drop for $name<C> {
+                fn drop(&mut self) {
+                    self.release();
+                }
+            }
+
             impl<C: GpuContext + ?Sized> $name<C> {
-                pub(crate) fn from_raw(resource: C::$res) -> Self {
-                    Self { resource }
+                pub(crate) fn from_raw(resource: C::$res, deletions: &DeletionQueue<C>) -> Self {
+                    Self {
+                        resource: Some(resource),
+                        deletions: deletions.clone(),
+                    }
                 }
 
                 pub(crate) fn resource(&self) -> &C::$res {
-                    &self.resource
+                    self.resource
+                        .as_ref()
+                        .expect("resource used after being released")
+                }
+
+                /// Queue the resource for deletion ahead of time.
+                pub(crate) fn release(&mut self) {
+                    if let Some(resource) = self.resource.take() {
+                        self.deletions.0.borrow_mut().$deleted.push(resource);
+                    }
                 }
             }
         )*
@@ -56,22 +84,72 @@ macro_rules! define_resource_wrappers {
 }
 
 define_resource_wrappers! {
-    Texture(Texture),
-    VertexBuffer(VertexBuffer),
+    Texture(Texture, textures),
+    VertexBuffer(VertexBuffer, vertex_buffers),
+}
+
+/// A queue of resources that have been dropped, but may still be in use by the GPU.
+///
+/// The resources are handed back to the [`GpuContext`] once the GPU has been flushed.
+pub(crate) struct DeletionQueue<C: GpuContext + ?Sized>(Rc<RefCell<Deleted<C>>>);
+
+/// The resources waiting to be deleted.
+struct Deleted<C: GpuContext + ?Sized> {
+    /// Textures waiting to be deleted.
+    textures: Vec<C::Texture>,
+
+    /// Vertex buffers waiting to be deleted.
+    vertex_buffers: Vec<C::VertexBuffer>,
+}
+
+impl<C: GpuContext + ?Sized> Clone for DeletionQueue<C> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<C: GpuContext + ?Sized> DeletionQueue<C> {
+    /// Create a new, empty deletion queue.
+    pub(crate) fn new() -> Self {
+        Self(Rc::new(RefCell::new(Deleted {
+            textures: Vec::new(),
+            vertex_buffers: Vec::new(),
+        })))
+    }
+
+    /// Delete all of the resources in the queue.
+    pub(crate) fn drain(&self, context: &mut C) {
+        let (textures, vertex_buffers) = {
+            let mut deleted = self.0.borrow_mut();
+            (
+                mem::take(&mut deleted.textures),
+                mem::take(&mut deleted.vertex_buffers),
+            )
+        };
+
+        for texture in textures {
+            context.delete_texture(texture);
+        }
+        for buffer in vertex_buffers {
+            context.delete_vertex_buffer(buffer);
+        }
+    }
 }
 
 impl<C: GpuContext + ?Sized> Texture<C> {
     pub(crate) fn new(
         context: &mut C,
         device: &C::Device,
+        deletions: &DeletionQueue<C>,
         interpolation: InterpolationMode,
         repeat: RepeatStrategy,
     ) -> Result<Self, C::Error> {
         let resource = context.create_texture(device, interpolation, repeat)?;
 
-        Ok(Self::from_raw(resource))
+        Ok(Self::from_raw(resource, deletions))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn write_linear_gradient(
         &self,
         context: &mut C,
@@ -80,7 +158,22 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         gradient: &FixedLinearGradient,
         size: Size,
         offset: Vec2,
+        dither: bool,
     ) -> Result<(), Pierror> {
+        let delta = gradient.end - gradient.start;
+        let length_squared = delta.hypot2();
+
+        // A zero-length gradient has no direction to dither along; let tiny-skia fill it with
+        // the last stop like it does for the undithered path.
+        if dither && length_squared > 0.0 {
+            let start = gradient.start + offset;
+
+            return self.write_dithered(context, device, queue, &gradient.stops, size, |x, y| {
+                let point = piet::kurbo::Point::new(x, y) - start;
+                Some(point.dot(delta) / length_squared)
+            });
+        }
+
         let shader = tiny_skia::LinearGradient::new(
             convert_to_ts_point(gradient.start),
             convert_to_ts_point(gradient.end),
@@ -94,11 +187,10 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         )
         .ok_or_else(|| Pierror::BackendError("Invalid error".into()))?;
 
-        self.write_shader(context, device, queue, shader, size);
-
-        Ok(())
+        self.write_shader(context, device, queue, shader, size)
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn write_radial_gradient(
         &self,
         context: &mut C,
@@ -107,7 +199,45 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         gradient: &FixedRadialGradient,
         size: Size,
         offset: Vec2,
+        dither: bool,
     ) -> Result<(), Pierror> {
+        if dither {
+            // This is a two-point conical gradient where the start circle has a radius of zero.
+            // Solve for the largest `t` where the point lies on the circle interpolated between
+            // the focal point and the end circle.
+            let focal = gradient.center + gradient.origin_offset + offset;
+            let delta = -gradient.origin_offset;
+            let radius = gradient.radius;
+            let a = delta.hypot2() - radius * radius;
+
+            return self.write_dithered(context, device, queue, &gradient.stops, size, |x, y| {
+                let point = piet::kurbo::Point::new(x, y) - focal;
+                let b = point.dot(delta);
+                let c = point.hypot2();
+
+                let t = if a.abs() < f64::EPSILON {
+                    if b <= 0.0 {
+                        return None;
+                    }
+                    c / (2.0 * b)
+                } else {
+                    let discriminant = b * b - a * c;
+                    if discriminant < 0.0 {
+                        return None;
+                    }
+                    let root = discriminant.sqrt();
+                    let (t1, t2) = ((b + root) / a, (b - root) / a);
+                    t1.max(t2)
+                };
+
+                if t < 0.0 {
+                    None
+                } else {
+                    Some(t)
+                }
+            });
+        }
+
         let shader = tiny_skia::RadialGradient::new(
             convert_to_ts_point(gradient.center + gradient.origin_offset),
             convert_to_ts_point(gradient.center),
@@ -122,9 +252,34 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         )
         .ok_or_else(|| Pierror::BackendError("Invalid error".into()))?;
 
-        self.write_shader(context, device, queue, shader, size);
+        self.write_shader(context, device, queue, shader, size)
+    }
+
+    /// Rasterize a gradient with dithering applied into this texture.
+    fn write_dithered(
+        &self,
+        context: &mut C,
+        device: &C::Device,
+        queue: &C::Queue,
+        stops: &[GradientStop],
+        size: Size,
+        position: impl Fn(f64, f64) -> Option<f64>,
+    ) -> Result<(), Pierror> {
+        let size = pad_size(size);
+        let size = (size.width as u32, size.height as u32);
+        let data = gradient::rasterize_dithered(size, stops, position);
 
-        Ok(())
+        self.write_texture(
+            context,
+            device,
+            queue,
+            size,
+            piet::ImageFormat::RgbaPremul,
+            Some(&data),
+        )
+        .piet_err()?;
+        self.set_interpolation(context, device, InterpolationMode::Bilinear)
+            .piet_err()
     }
 
     pub(crate) fn write_shader(
@@ -133,31 +288,22 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         device: &C::Device,
         queue: &C::Queue,
         shader: Shader<'_>,
-        mut size: Size,
-    ) {
-        // Pad the size out to at least one.
-        if (size.width as isize) < 1 {
-            size.width = 1.0;
-        }
-        if (size.height as isize) < 1 {
-            size.height = 1.0;
-        }
+        size: Size,
+    ) -> Result<(), Pierror> {
+        let size = pad_size(size);
 
         // Create a pixmap to render the shader into.
         let mut pixmap =
-            Pixmap::new(size.width as _, size.height as _).expect("failed to create pixmap");
+            Pixmap::new(size.width as _, size.height as _).ok_or(Pierror::InvalidInput)?;
+        let rect = tiny_skia::Rect::from_xywh(0.0, 0.0, size.width as _, size.height as _)
+            .ok_or(Pierror::InvalidInput)?;
 
         // Render the shader into the pixmap.
         let paint = Paint {
             shader,
             ..Default::default()
         };
-        pixmap.fill_rect(
-            tiny_skia::Rect::from_xywh(0.0, 0.0, size.width as _, size.height as _).unwrap(),
-            &paint,
-            tiny_skia::Transform::identity(),
-            None,
-        );
+        pixmap.fill_rect(rect, &paint, tiny_skia::Transform::identity(), None);
 
         // Write the pixmap into the texture.
         let data = pixmap.take();
@@ -168,8 +314,10 @@ impl<C: GpuContext + ?Sized> Texture<C> {
             (size.width as _, size.height as _),
             piet::ImageFormat::RgbaPremul,
             Some(&data),
-        );
-        self.set_interpolation(context, device, InterpolationMode::Bilinear);
+        )
+        .piet_err()?;
+        self.set_interpolation(context, device, InterpolationMode::Bilinear)
+            .piet_err()
     }
 
     pub(crate) fn write_texture(
@@ -180,15 +328,15 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         size: (u32, u32),
         format: piet::ImageFormat,
         data: Option<&[u8]>,
-    ) {
+    ) -> Result<(), C::Error> {
         context.write_texture(crate::gpu_backend::TextureWrite {
             device,
             queue,
             size,
             format,
             data,
-            texture: &self.resource,
-        });
+            texture: self.resource(),
+        })
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -201,7 +349,7 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         size: (u32, u32),
         format: piet::ImageFormat,
         data: &[u8],
-    ) {
+    ) -> Result<(), C::Error> {
         context.write_subtexture(crate::gpu_backend::SubtextureWrite {
             device,
             queue,
@@ -209,8 +357,17 @@ impl<C: GpuContext + ?Sized> Texture<C> {
             size,
             format,
             data,
-            texture: &self.resource,
-        });
+            texture: self.resource(),
+        })
+    }
+
+    pub(crate) fn generate_mipmaps(
+        &self,
+        context: &mut C,
+        device: &C::Device,
+        queue: &C::Queue,
+    ) -> Result<bool, C::Error> {
+        context.generate_mipmaps(device, queue, self.resource())
     }
 
     pub(crate) fn set_interpolation(
@@ -218,15 +375,19 @@ impl<C: GpuContext + ?Sized> Texture<C> {
         context: &mut C,
         device: &C::Device,
         interpolation: InterpolationMode,
-    ) {
-        context.set_texture_interpolation(device, self.resource(), interpolation);
+    ) -> Result<(), C::Error> {
+        context.set_texture_interpolation(device, self.resource(), interpolation)
     }
 }
 
 impl<C: GpuContext + ?Sized> VertexBuffer<C> {
-    pub(crate) fn new(context: &mut C, device: &C::Device) -> Result<Self, C::Error> {
+    pub(crate) fn new(
+        context: &mut C,
+        device: &C::Device,
+        deletions: &DeletionQueue<C>,
+    ) -> Result<Self, C::Error> {
         let resource = context.create_vertex_buffer(device)?;
-        Ok(Self::from_raw(resource))
+        Ok(Self::from_raw(resource, deletions))
     }
 
     pub(crate) fn upload(
@@ -236,11 +397,89 @@ impl<C: GpuContext + ?Sized> VertexBuffer<C> {
         queue: &C::Queue,
         data: &[Vertex],
         indices: &[u32],
-    ) {
+    ) -> Result<(), C::Error> {
         context.write_vertices(device, queue, self.resource(), data, indices)
     }
 }
 
+/// The number of vertex buffers to use between flushes before reusing them anyway.
+const MAX_VERTEX_BUFFERS_IN_FLIGHT: usize = 64;
+
+/// A pool of vertex buffers.
+///
+/// Every draw gets its own buffer, so uploading the next draw never has to wait for the GPU to
+/// finish with the previous one. Buffers are recycled once the GPU has been flushed.
+pub(crate) struct VertexBufferPool<C: GpuContext + ?Sized> {
+    /// Buffers that the GPU is done with.
+    free: Vec<VertexBuffer<C>>,
+
+    /// Buffers that have been drawn with since the last flush, from oldest to newest.
+    used: VecDeque<VertexBuffer<C>>,
+}
+
+impl<C: GpuContext + ?Sized> VertexBufferPool<C> {
+    /// Create a new pool, starting with a single buffer.
+    pub(crate) fn new(
+        context: &mut C,
+        device: &C::Device,
+        deletions: &DeletionQueue<C>,
+    ) -> Result<Self, C::Error> {
+        Ok(Self {
+            free: vec![VertexBuffer::new(context, device, deletions)?],
+            used: VecDeque::new(),
+        })
+    }
+
+    /// Get a buffer to upload a draw into.
+    ///
+    /// If too many buffers have been used since the last flush, the oldest one is reused.
+    pub(crate) fn acquire(
+        &mut self,
+        context: &mut C,
+        device: &C::Device,
+        deletions: &DeletionQueue<C>,
+    ) -> Result<VertexBuffer<C>, C::Error> {
+        if let Some(buffer) = self.free.pop() {
+            return Ok(buffer);
+        }
+
+        if self.used.len() >= MAX_VERTEX_BUFFERS_IN_FLIGHT {
+            if let Some(buffer) = self.used.pop_front() {
+                return Ok(buffer);
+            }
+        }
+
+        VertexBuffer::new(context, device, deletions)
+    }
+
+    /// Return a buffer that has been drawn with.
+    pub(crate) fn retire(&mut self, buffer: VertexBuffer<C>) {
+        self.used.push_back(buffer);
+    }
+
+    /// Indicate that the GPU queue has been flushed, so every buffer can be reused.
+    pub(crate) fn gpu_flushed(&mut self) {
+        self.free.extend(self.used.drain(..));
+    }
+
+    /// Drop all of the buffers.
+    pub(crate) fn clear(&mut self) {
+        self.free.clear();
+        self.used.clear();
+    }
+}
+
+/// Pad the size out to at least one pixel in each dimension.
+fn pad_size(mut size: Size) -> Size {
+    if (size.width as isize) < 1 {
+        size.width = 1.0;
+    }
+    if (size.height as isize) < 1 {
+        size.height = 1.0;
+    }
+    size
+}
+
 pub(crate) fn convert_to_ts_point(point: piet::kurbo::Point) -> tiny_skia::Point {
     tiny_skia::Point {
         x: point.x as f32,
@@ -249,9 +488,9 @@ pub(crate) fn convert_to_ts_point(point: piet::kurbo::Point) -> tiny_skia::Point
 }
 
 pub(crate) fn convert_to_ts_color(color: piet::Color) -> tiny_skia::Color {
-    let (r, g, b, a) = color.as_rgba();
+    let (r, g, b, a) = color.as_rgba8();
 
-    tiny_skia::Color::from_rgba(r as f32, g as f32, b as f32, a as f32).expect("Invalid color")
+    tiny_skia::Color::from_rgba8(r, g, b, a)
 }
 
 pub(crate) fn convert_to_ts_gradient_stop(grad_stop: &GradientStop) -> tiny_skia::GradientStop {